@@ -0,0 +1,107 @@
+// Queries through index::search, and filtering and sorting all documents
+// of an index, the work search_items does per hit, with documents kept
+// parsed versus parsed from a JSON string on every access as they used to
+// be.
+//
+//   cargo bench --bench documents
+
+#![feature(test)]
+
+extern crate test;
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use serde_json::{json, Value};
+use test::Bencher;
+
+use gianna::index;
+
+const COLORS: [&str; 6] = ["red", "green", "blue", "black", "white", "yellow"];
+const KINDS: [&str; 5] = ["dress", "shirt", "shoes", "hat", "jacket"];
+
+fn build_index(count: usize) -> index::Index {
+  let fields = serde_json::from_value(json!(["name", "description"])).unwrap();
+  let mut target = index::create(fields, Default::default());
+
+  for i in 0..count {
+    let item = json!({
+      "_id": i.to_string(),
+      "name": format!("{} {}", COLORS[i % 6], KINDS[i % 5]),
+      "description": format!("A {} {} in size {}", COLORS[(i / 6) % 6], KINDS[(i / 5) % 5], i % 40),
+      "price": (i * 7919 % 10000) as f64 / 100.0,
+      "tags": [COLORS[i % 6], KINDS[i % 5]],
+    });
+    index::add_object(&mut target, item, index::OnConflict::Reject).unwrap();
+  }
+
+  return target;
+}
+
+fn filter_and_sort(docs: Vec<&Value>) -> usize {
+  let mut docs: Vec<&Value> = docs
+    .into_iter()
+    .filter(|x| x["price"].as_f64().unwrap_or(0.0) > 25.0)
+    .collect();
+  docs.sort_by(|a, b| {
+    let a_val = a["price"].as_f64().unwrap_or(0.0);
+    let b_val = b["price"].as_f64().unwrap_or(0.0);
+    a_val.partial_cmp(&b_val).unwrap_or(Ordering::Equal)
+  });
+  return docs.len();
+}
+
+#[bench]
+fn stored(b: &mut Bencher) {
+  let target = build_index(20000);
+
+  b.iter(|| {
+    let docs: Vec<&Value> = target.items.values().collect();
+    filter_and_sort(docs)
+  });
+}
+
+#[bench]
+fn reparsed(b: &mut Bencher) {
+  let target = build_index(20000);
+  let strings: HashMap<u32, String> = target.items
+    .iter()
+    .map(|(iid, item)| (*iid, item.to_string()))
+    .collect();
+
+  b.iter(|| {
+    let parsed: Vec<Value> = strings
+      .values()
+      .map(|x| serde_json::from_str(x).unwrap())
+      .collect();
+    filter_and_sort(parsed.iter().collect())
+  });
+}
+
+fn search(target: &index::Index, query: &str) -> usize {
+  index::search(target, String::from(query), false, &|_| true, false).unwrap().hits.len()
+}
+
+#[bench]
+fn query_words(b: &mut Bencher) {
+  let target = build_index(20000);
+  b.iter(|| search(&target, "red dress"));
+}
+
+#[bench]
+fn query_phrase(b: &mut Bencher) {
+  let target = build_index(20000);
+  b.iter(|| search(&target, "\"blue jacket\" size"));
+}
+
+#[bench]
+fn query_prefix_and_typos(b: &mut Bencher) {
+  let target = build_index(20000);
+  b.iter(|| search(&target, "yelow jack*"));
+}
+
+#[bench]
+fn query_filtered(b: &mut Bencher) {
+  let target = build_index(20000);
+  let keep = |x: &Value| x["price"].as_f64().unwrap_or(0.0) > 25.0;
+  b.iter(|| index::search(&target, String::from("green shoes"), false, &keep, false).unwrap().hits.len());
+}
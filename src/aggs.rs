@@ -0,0 +1,246 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use serde_json::{Map, Value};
+
+use crate::dot_notation;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RangeBucket {
+  // Inclusive
+  from: Option<f64>,
+  // Exclusive
+  to: Option<f64>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Aggregation {
+  // Most common values with their counts
+  Terms { property: String, size: Option<usize> },
+  // Counts per explicit numeric range
+  Range { property: String, ranges: Vec<RangeBucket> },
+  // Counts per fixed size numeric bucket
+  Histogram { property: String, interval: f64 },
+  // count, min, max, avg and sum
+  Stats { property: String },
+}
+
+// Values at a path, with arrays expanded into their elements
+fn values_at(doc: &Value, property: &str) -> Vec<Value> {
+  match dot_notation(doc, String::from(property)) {
+    Value::Null => vec![],
+    Value::Array(values) => values.into_iter().filter(|x| !x.is_null()).collect(),
+    value => vec![value],
+  }
+}
+
+fn numbers_at(doc: &Value, property: &str) -> Vec<f64> {
+  values_at(doc, property)
+    .iter()
+    .filter_map(|x| x.as_f64())
+    .collect()
+}
+
+fn terms(docs: &Vec<&Value>, property: &str, size: usize) -> Value {
+  // Keyed by the serialized value so strings, numbers and booleans can be
+  // counted side by side
+  let mut counts: HashMap<String, (Value, u64)> = HashMap::new();
+
+  for doc in docs {
+    // A document counts once per distinct value
+    let mut seen = HashSet::new();
+    for value in values_at(doc, property) {
+      let key = value.to_string();
+      if seen.insert(key.clone()) {
+        counts.entry(key).or_insert((value, 0)).1 += 1;
+      }
+    }
+  }
+
+  let mut buckets: Vec<(String, (Value, u64))> = counts.into_iter().collect();
+  buckets.sort_by(|a, b| (b.1).1.cmp(&(a.1).1).then_with(|| a.0.cmp(&b.0)));
+
+  let other_count: u64 = buckets.iter().skip(size).map(|x| (x.1).1).sum();
+  let buckets: Vec<Value> = buckets
+    .into_iter()
+    .take(size)
+    .map(|(_, (value, count))| serde_json::json!({ "value": value, "count": count }))
+    .collect();
+
+  return serde_json::json!({
+    "buckets": buckets,
+    "other_count": other_count,
+  });
+}
+
+fn range(docs: &Vec<&Value>, property: &str, ranges: &Vec<RangeBucket>) -> Value {
+  let mut counts = vec![0u64; ranges.len()];
+
+  for doc in docs {
+    let numbers = numbers_at(doc, property);
+    for (i, range) in ranges.iter().enumerate() {
+      let matches = numbers.iter().any(|x| {
+        range.from.map_or(true, |from| *x >= from) && range.to.map_or(true, |to| *x < to)
+      });
+      if matches {
+        counts[i] += 1;
+      }
+    }
+  }
+
+  let buckets: Vec<Value> = ranges
+    .iter()
+    .zip(counts)
+    .map(|(range, count)| serde_json::json!({ "from": range.from, "to": range.to, "count": count }))
+    .collect();
+
+  return serde_json::json!({ "buckets": buckets });
+}
+
+fn histogram(docs: &Vec<&Value>, property: &str, interval: f64) -> Value {
+  // Bucket index to count, ordered
+  let mut counts: BTreeMap<i64, u64> = BTreeMap::new();
+
+  for doc in docs {
+    let mut keys: Vec<i64> = numbers_at(doc, property)
+      .iter()
+      .map(|x| (x / interval).floor() as i64)
+      .collect();
+    keys.sort_unstable();
+    keys.dedup();
+    for key in keys {
+      *counts.entry(key).or_insert(0) += 1;
+    }
+  }
+
+  let buckets: Vec<Value> = counts
+    .into_iter()
+    .map(|(key, count)| serde_json::json!({ "key": key as f64 * interval, "count": count }))
+    .collect();
+
+  return serde_json::json!({ "buckets": buckets });
+}
+
+fn stats(docs: &Vec<&Value>, property: &str) -> Value {
+  let numbers: Vec<f64> = docs
+    .iter()
+    .flat_map(|doc| numbers_at(doc, property))
+    .collect();
+
+  if numbers.len() == 0 {
+    return serde_json::json!({ "count": 0, "min": null, "max": null, "avg": null, "sum": 0.0 });
+  }
+
+  let sum: f64 = numbers.iter().sum();
+  let min = numbers.iter().cloned().min_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+  let max = numbers.iter().cloned().max_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+
+  return serde_json::json!({
+    "count": numbers.len(),
+    "min": min,
+    "max": max,
+    "avg": sum / numbers.len() as f64,
+    "sum": sum,
+  });
+}
+
+pub fn validate(aggregations: &HashMap<String, Aggregation>) -> Result<(), String> {
+  for (name, aggregation) in aggregations.iter() {
+    if let Aggregation::Histogram { interval, .. } = aggregation {
+      if !(*interval > 0.0) {
+        return Err(format!("Aggregation '{}': interval must be greater than 0", name));
+      }
+    }
+  }
+  return Ok(());
+}
+
+pub fn compute(aggregations: &HashMap<String, Aggregation>, docs: &Vec<&Value>) -> Value {
+  let mut results = Map::new();
+
+  for (name, aggregation) in aggregations.iter() {
+    let result = match aggregation {
+      Aggregation::Terms { property, size } => terms(docs, property, size.unwrap_or(10)),
+      Aggregation::Range { property, ranges } => range(docs, property, ranges),
+      Aggregation::Histogram { property, interval } => histogram(docs, property, *interval),
+      Aggregation::Stats { property } => stats(docs, property),
+    };
+    results.insert(name.clone(), result);
+  }
+
+  return Value::Object(results);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn docs() -> Vec<Value> {
+    vec![
+      json!({"color": "red", "tags": ["a", "b", "a"], "price": 5}),
+      json!({"color": "blue", "tags": ["a"], "price": 10}),
+      json!({"color": "red", "tags": [], "price": 15.5}),
+      json!({"color": true, "price": -3, "stock": {"count": 2}}),
+    ]
+  }
+
+  fn aggregate(aggregation: Value) -> Value {
+    let docs = docs();
+    let aggregations: HashMap<String, Aggregation> = serde_json::from_value(json!({ "x": aggregation })).unwrap();
+    validate(&aggregations).unwrap();
+    return compute(&aggregations, &docs.iter().collect())["x"].take();
+  }
+
+  #[test]
+  fn terms_count_documents_per_value() {
+    let colors = aggregate(json!({"type": "terms", "property": "color", "size": 2}));
+    assert_eq!(colors, json!({
+      "buckets": [{"value": "red", "count": 2}, {"value": "blue", "count": 1}],
+      "other_count": 1,
+    }));
+
+    // A value twice in one document counts once
+    let tags = aggregate(json!({"type": "terms", "property": "tags"}));
+    assert_eq!(tags["buckets"], json!([{"value": "a", "count": 2}, {"value": "b", "count": 1}]));
+    assert_eq!(tags["other_count"], 0);
+  }
+
+  #[test]
+  fn ranges_include_from_and_exclude_to() {
+    let ranges = json!([{"to": 10}, {"from": 10, "to": 15.5}, {"from": 15.5}]);
+    let prices = aggregate(json!({"type": "range", "property": "price", "ranges": ranges}));
+    let counts: Vec<&Value> = prices["buckets"].as_array().unwrap().iter().map(|x| &x["count"]).collect();
+    assert_eq!(counts, vec![2, 1, 1]);
+  }
+
+  #[test]
+  fn histogram_buckets_round_down() {
+    let prices = aggregate(json!({"type": "histogram", "property": "price", "interval": 10}));
+    assert_eq!(prices["buckets"], json!([
+      {"key": -10.0, "count": 1},
+      {"key": 0.0, "count": 1},
+      {"key": 10.0, "count": 2},
+    ]));
+
+    for interval in [json!(0), json!(-1)].iter() {
+      let aggregations: HashMap<String, Aggregation> = serde_json::from_value(json!({
+        "x": {"type": "histogram", "property": "price", "interval": interval}
+      })).unwrap();
+      assert!(validate(&aggregations).is_err());
+    }
+  }
+
+  #[test]
+  fn stats_of_numbers() {
+    let prices = aggregate(json!({"type": "stats", "property": "price"}));
+    assert_eq!(prices, json!({"count": 4, "min": -3.0, "max": 15.5, "avg": 6.875, "sum": 27.5}));
+
+    let stock = aggregate(json!({"type": "stats", "property": "stock.count"}));
+    assert_eq!(stock["count"], 1);
+    assert_eq!(stock["sum"], 2.0);
+
+    let missing = aggregate(json!({"type": "stats", "property": "weight"}));
+    assert_eq!(missing, json!({"count": 0, "min": null, "max": null, "avg": null, "sum": 0.0}));
+  }
+}
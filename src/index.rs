@@ -1,65 +1,378 @@
-use std::collections::{HashMap, VecDeque};
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque};
+use std::mem;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
 use serde_json::{Value};
 use sublime_fuzzy::{FuzzySearch};
 
-use crate::lp::{gramify, clean_words};
+use crate::lp::{fold_chars, Analyzer, Language};
+use crate::postings::{DecodedList, Posting, PostingList, Postings};
+use crate::query;
+use crate::query::{Node, Occur, Query, Words};
+use crate::synonyms::Synonyms;
+use crate::levenshtein;
+use crate::levenshtein::Automaton;
+use crate::trie;
+use crate::trie::Trie;
 
-fn parse_json(datastr: String) -> Value {
-  return serde_json::from_str(&datastr).unwrap();
+// Every matching trigram adds a flat weight, they only find candidates
+const GRAM_WEIGHT: f32 = 1.0;
+// Scales BM25 word scores so that a rare word still outweighs the
+// trigrams it is made of, like the old fixed weight of 50 did
+const WORD_WEIGHT: f32 = 10.0;
+// Added for a quoted phrase found in a field, divided by one more than the
+// number of positions its words are apart
+const PHRASE_WEIGHT: f32 = 10.0;
+// Scales the score of an indexed word once per typo it is away from the
+// word in the query
+const TYPO_WEIGHT: f32 = 0.5;
+
+// Bumped whenever tokenization changes in a way that needs documents to be
+// indexed again. 1: Unicode words, accent folding and languages.
+// 2: UAX #29 word boundaries and CJK runs. 3: words starting with '#'
+// kept apart from trigram keys.
+pub const ANALYSIS_VERSION: u32 = 3;
+
+// Documents are kept parsed in memory but stored as JSON strings in
+// snapshots, as they always have been
+mod stored_items {
+  use std::collections::HashMap;
+  use serde::de::{Deserialize, Deserializer, Error};
+  use serde::ser::Serializer;
+  use serde_json::Value;
+
+  pub fn serialize<S: Serializer>(items: &HashMap<u32, Value>, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(items.iter().map(|(iid, item)| (iid, item.to_string())))
+  }
+
+  pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<HashMap<u32, Value>, D::Error> {
+    let stored: HashMap<u32, String> = HashMap::deserialize(deserializer)?;
+    let mut items = HashMap::with_capacity(stored.len());
+    for (iid, item) in stored {
+      items.insert(iid, serde_json::from_str(&item).map_err(D::Error::custom)?);
+    }
+    return Ok(items);
+  }
+}
+
+fn default_k1() -> f32 {
+  1.2
+}
+
+fn default_b() -> f32 {
+  0.75
+}
+
+fn default_max_candidates() -> usize {
+  1000
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Settings {
+  // BM25 term frequency saturation
+  #[serde(default = "default_k1")]
+  pub k1: f32,
+  // BM25 document length normalization
+  #[serde(default = "default_b")]
+  pub b: f32,
+  // Most documents that get fuzzy matched per query, the best by token score
+  #[serde(default = "default_max_candidates")]
+  pub max_candidates: usize,
+  // Stemmer for fields that don't set their own, changing it reindexes
+  #[serde(default)]
+  pub language: Language,
+  // Index runs of CJK chars as overlapping pairs instead of single chars,
+  // for fields that don't set an analyzer. Changing it reindexes.
+  #[serde(default)]
+  pub cjk_bigrams: bool,
+  // Named pipelines fields can use instead of the standard one, changing
+  // them reindexes
+  #[serde(default)]
+  pub analyzers: BTreeMap<String, Analyzer>,
+  // Also complete whole field values as they are typed, like product
+  // names, not just words
+  #[serde(default)]
+  pub complete_values: bool,
+}
+
+impl Default for Settings {
+  fn default() -> Settings {
+    Settings {
+      k1: default_k1(),
+      b: default_b(),
+      max_candidates: default_max_candidates(),
+      language: Language::default(),
+      cjk_bigrams: false,
+      analyzers: BTreeMap::new(),
+      complete_values: false,
+    }
+  }
+}
+
+fn default_boost() -> f32 {
+  1.0
+}
+
+// Fields are given either as a plain name or as
+// {"name": ..., "boost": ..., "language": ..., "analyzer": ...}
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FieldDefinition {
+  Name(String),
+  Weighted {
+    name: String,
+    #[serde(default = "default_boost")]
+    boost: f32,
+    #[serde(default)]
+    language: Option<Language>,
+    #[serde(default)]
+    analyzer: Option<String>,
+  },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "FieldDefinition")]
+pub struct Field {
+  pub name: String,
+  pub boost: f32,
+  // Overrides the language of the index
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub language: Option<Language>,
+  // Name of one of the analyzers in the settings
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub analyzer: Option<String>,
+}
+
+impl From<FieldDefinition> for Field {
+  fn from(definition: FieldDefinition) -> Field {
+    match definition {
+      FieldDefinition::Name(name) => Field { name, boost: default_boost(), language: None, analyzer: None },
+      FieldDefinition::Weighted { name, boost, language, analyzer } => Field { name, boost, language, analyzer },
+    }
+  }
+}
+
+pub fn field_analyzer(index: &Index, field: usize) -> Cow<'_, Analyzer> {
+  let field = &index.fields[field];
+  match &field.analyzer {
+    Some(name) => Cow::Borrowed(&index.settings.analyzers[name]),
+    None => Cow::Owned(Analyzer::standard(
+      field.language.unwrap_or(index.settings.language),
+      index.settings.cjk_bigrams,
+    )),
+  }
+}
+
+// Fields grouped by the analyzer their text goes through, in order of
+// their first field
+pub fn analyzers(index: &Index) -> Vec<(Cow<'_, Analyzer>, Vec<usize>)> {
+  let mut analyzers: Vec<(Cow<Analyzer>, Vec<usize>)> = Vec::new();
+  for field in 0..index.fields.len() {
+    let analyzer = field_analyzer(index, field);
+    match analyzers.iter_mut().find(|x| x.0 == analyzer) {
+      Some(group) => group.1.push(field),
+      None => analyzers.push((analyzer, vec![field])),
+    }
+  }
+  return analyzers;
+}
+
+// Whether documents have to be indexed again for new settings
+pub fn analysis_changed(old: &Settings, new: &Settings) -> bool {
+  old.language != new.language || old.cjk_bigrams != new.cjk_bigrams || old.analyzers != new.analyzers
+}
+
+// Postings store the field as a u8
+const MAX_FIELDS: usize = 256;
+
+// Checks the number of fields and their boosts, the BM25 parameters, the
+// analyzers and that the fields only name existing ones
+pub fn validate_settings(fields: &Vec<Field>, settings: &mut Settings) -> Result<(), String> {
+  if fields.len() > MAX_FIELDS {
+    return Err(format!("An index can have at most {} fields, got {}", MAX_FIELDS, fields.len()));
+  }
+  // Scales scores like k1 and b, a boost of 0 or less breaks ranking too
+  for field in fields {
+    if !field.boost.is_finite() || field.boost <= 0.0 {
+      return Err(format!("Field '{}' needs a boost above 0, got {}", field.name, field.boost));
+    }
+  }
+
+  // A negative k1 or a b outside of [0, 1] turns scores negative or
+  // infinite, and NaN breaks sorting
+  if !settings.k1.is_finite() || settings.k1 < 0.0 {
+    return Err(format!("k1 has to be a number of at least 0, got {}", settings.k1));
+  }
+  if !settings.b.is_finite() || settings.b < 0.0 || settings.b > 1.0 {
+    return Err(format!("b has to be a number from 0 to 1, got {}", settings.b));
+  }
+
+  for (name, analyzer) in settings.analyzers.iter_mut() {
+    if let Err(message) = analyzer.validate() {
+      return Err(format!("Analyzer '{}': {}", name, message));
+    }
+  }
+
+  for field in fields {
+    if let Some(name) = &field.analyzer {
+      if !settings.analyzers.contains_key(name) {
+        return Err(format!("Field '{}' uses the unknown analyzer '{}'", field.name, name));
+      }
+      if field.language.is_some() {
+        return Err(format!("Field '{}' sets both a language and an analyzer", field.name));
+      }
+    }
+  }
+  return Ok(());
+}
+
+// What to do when an added item has the _id of an existing one
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnConflict {
+  Reject,
+  Upsert,
+}
+
+impl Default for OnConflict {
+  fn default() -> OnConflict {
+    OnConflict::Reject
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemStatus {
+  Created,
+  Updated,
+  Deleted,
+  NotFound,
+  Error,
+  // Valid, but not applied because another item of an atomic batch failed
+  Skipped,
 }
 
+#[derive(Serialize, Deserialize)]
 pub struct Index {
   pub id_counter: u32,
-  pub items: HashMap<u32, String>,
-  pub token_scoring: HashMap<String, Vec<(u32, u8)>>,
+  #[serde(with = "stored_items")]
+  pub items: HashMap<u32, Value>,
+  // Token to (id, field, term frequency), with one entry per field the
+  // token occurs in. Postings are sorted by id, so the postings of one
+  // document are always adjacent.
+  // Trigram tokens (see gram_key) have a term frequency of 1.
+  pub token_scoring: Postings,
+  // Term ids each document is indexed under, so removing a document only
+  // touches its own posting lists. Rebuilt from token_scoring on load.
+  #[serde(skip)]
+  pub doc_tokens: HashMap<u32, Vec<u32>>,
   pub id_map: HashMap<String, u32>,
-  pub fields: Vec<String>,
-  pub query_times: VecDeque<(u64, u64)>
+  pub fields: Vec<Field>,
+  #[serde(default)]
+  pub settings: Settings,
+  // Expanded in queries, see query::parse
+  #[serde(default)]
+  pub synonyms: Vec<Synonyms>,
+  // Number of words per field, per document and over all documents
+  #[serde(default)]
+  pub doc_lengths: HashMap<u32, Vec<u32>>,
+  #[serde(default)]
+  pub total_lengths: Vec<u64>,
+  // Sequence number of the last logged operation applied to this index
+  #[serde(default)]
+  pub last_seq: u64,
+  // How the documents were tokenized, older snapshots get reindexed
+  #[serde(default)]
+  pub analysis_version: u32,
+  // Words and field values with the number of documents they are in, for
+  // search as you type. Rebuilt from the documents on load.
+  #[serde(skip)]
+  pub completions: Trie,
+  #[serde(skip)]
+  pub value_completions: Trie,
+  #[serde(skip)]
+  pub query_times: Mutex<VecDeque<(u64, u64)>>,
+  // Set whenever the index changes, cleared once a snapshot has been written
+  #[serde(skip)]
+  pub dirty: AtomicBool,
+  // Set once the index is removed from the registry, writers that looked
+  // it up before then have to look its name up again
+  #[serde(skip)]
+  pub dropped: bool,
 }
 
 pub fn clear(index: &mut Index) {
   index.id_counter = 0;
   index.items = HashMap::new();
-  index.token_scoring = HashMap::new();
+  index.token_scoring = Postings::default();
+  index.doc_tokens = HashMap::new();
   index.id_map = HashMap::new();
+  index.doc_lengths = HashMap::new();
+  index.total_lengths = vec![0; index.fields.len()];
+  index.completions = Trie::default();
+  index.value_completions = Trie::default();
+  index.dirty.store(true, Ordering::Relaxed);
 }
 
-pub fn create(fields: Vec<String>) -> Index {
+pub fn create(fields: Vec<Field>, settings: Settings) -> Index {
+  let num_fields = fields.len();
+
   Index {
     id_counter: 0,
     items: HashMap::new(),
-    token_scoring: HashMap::new(),
+    token_scoring: Postings::default(),
+    doc_tokens: HashMap::new(),
     id_map: HashMap::new(),
     fields,
-    query_times: VecDeque::new()
+    settings,
+    synonyms: Vec::new(),
+    doc_lengths: HashMap::new(),
+    total_lengths: vec![0; num_fields],
+    last_seq: 0,
+    analysis_version: ANALYSIS_VERSION,
+    completions: Trie::default(),
+    value_completions: Trie::default(),
+    query_times: Mutex::new(VecDeque::new()),
+    dirty: AtomicBool::new(true),
+    dropped: false,
   }
 }
 
-pub fn extract_fields(obj: &Value, fields: &Vec<String>) -> String {
+pub fn extract_fields(obj: &Value, fields: &Vec<Field>) -> String {
   let mut token_str = String::from("");
-  
-  for prop in fields.iter() {
-    let value = &obj[prop];
-    if value.is_string() {
-      token_str.push_str(value.as_str().unwrap());
-      token_str.push(' ');
-    }
-    else if value.is_array() {
-      let vec: Vec<_> = value.as_array().unwrap().to_vec();
-      for el in vec {
-        if el.is_string() {
-          token_str.push_str(el.as_str().unwrap());
-          token_str.push(' ');
-        }
+
+  for field in fields.iter() {
+    token_str.push_str(&extract_field(obj, &field.name));
+  }
+
+  return token_str;
+}
+
+pub fn extract_field(obj: &Value, prop: &str) -> String {
+  let mut token_str = String::from("");
+
+  let value = &obj[prop];
+  if value.is_string() {
+    token_str.push_str(value.as_str().unwrap());
+    token_str.push(' ');
+  }
+  else if value.is_array() {
+    let vec: Vec<_> = value.as_array().unwrap().to_vec();
+    for el in vec {
+      if el.is_string() {
+        token_str.push_str(el.as_str().unwrap());
+        token_str.push(' ');
       }
     }
-    else if value.is_object() {
-      let map = value.as_object().unwrap();
-      for el in map.values() {
-        if el.is_string() {
-          token_str.push_str(el.as_str().unwrap());
-          token_str.push(' ');
-        }
+  }
+  else if value.is_object() {
+    let map = value.as_object().unwrap();
+    for el in map.values() {
+      if el.is_string() {
+        token_str.push_str(el.as_str().unwrap());
+        token_str.push(' ');
       }
     }
   }
@@ -67,6 +380,10 @@ pub fn extract_fields(obj: &Value, fields: &Vec<String>) -> String {
   return token_str;
 }
 
+pub fn get<'a>(index: &'a Index, id: &str) -> Option<&'a Value> {
+  index.id_map.get(id).and_then(|iid| index.items.get(iid))
+}
+
 pub fn remove(index: &mut Index, id: String) -> bool {
   let iid_maybe = index.id_map.get(&id.clone());
 
@@ -76,156 +393,1707 @@ pub fn remove(index: &mut Index, id: String) -> bool {
 
   let iid = *iid_maybe.unwrap();
 
+  unindex_item(index, iid);
   index.items.remove(&iid);
   index.id_map.remove(&id);
 
-  for value in index.token_scoring.values_mut() {
-    value.retain(|x| x.0 != iid);
+  return true;
+}
+
+// Returns the _id of an item that can be stored
+pub fn validate_item(obj: &Value) -> Result<String, String> {
+  if !obj.is_object() {
+    return Err(String::from("Item must be an object"));
   }
 
-  index.token_scoring.retain(|_, x| x.len() > 0);
+  match &obj["_id"] {
+    Value::String(id) if id.len() > 0 => Ok(id.clone()),
+    Value::String(_) => Err(String::from("_id must not be empty")),
+    Value::Null => Err(String::from("_id is missing")),
+    _ => Err(String::from("_id must be a string")),
+  }
+}
 
-  return true;
+pub fn conflict_error(id: &str) -> String {
+  format!("An item with _id '{}' already exists", id)
 }
 
-pub fn update(index: &mut Index, obj: Value) {
-  let token_str = extract_fields(&obj, &index.fields);
-  let id = &obj["_id"].as_str().unwrap();
-  let iid = *index.id_map.get(id.clone()).unwrap();
+// Creates the item when no item with its _id exists yet
+pub fn update(index: &mut Index, obj: Value) -> Result<ItemStatus, String> {
+  let id = validate_item(&obj)?;
 
-  for value in index.token_scoring.values_mut() {
-    value.retain(|x| x.0 != iid);
+  if !index.id_map.contains_key(&id) {
+    add(index, id, obj);
+    return Ok(ItemStatus::Created);
   }
 
-  index.token_scoring.retain(|_, x| x.len() > 0);
+  let iid = *index.id_map.get(&id).unwrap();
 
-  index.items.insert(iid as u32, obj.to_string());
-  index_item(index, iid, token_str.trim().to_string());
+  unindex_item(index, iid);
+
+  index_item(index, iid, &obj);
+  index.items.insert(iid as u32, obj);
+  return Ok(ItemStatus::Updated);
 }
 
-pub fn add_object(index: &mut Index, obj: Value) {
-  let token_str = extract_fields(&obj, &index.fields);
-  let id = &obj["_id"].as_str().unwrap();
+pub fn add_object(index: &mut Index, obj: Value, on_conflict: OnConflict) -> Result<ItemStatus, String> {
+  let id = validate_item(&obj)?;
+
+  if index.id_map.contains_key(&id) {
+    if on_conflict == OnConflict::Upsert {
+      return update(index, obj);
+    }
+    return Err(conflict_error(&id));
+  }
 
   add(
     index,
-    id.to_string(),
-    obj.to_string(),
-    token_str.trim().to_string()
+    id,
+    obj
   );
+  return Ok(ItemStatus::Created);
 }
 
-fn add(index: &mut Index, id: String, obj: String, to_tokenize: String) {
+fn add(index: &mut Index, id: String, obj: Value) {
   let iid = index.id_counter;
   index.id_map.insert(id, iid);
   index.id_counter += 1;
-  
+
+  index_item(index, iid, &obj);
   index.items.insert(iid as u32, obj);
-  index_item(index, iid, to_tokenize);
 }
 
-fn index_item(index: &mut Index, iid: u32, to_tokenize: String) {
-  let mut grams = gramify(to_tokenize.to_string());
-  grams.sort_unstable();
-  grams.dedup();
+// Documents that spell out a synonym phrase with words too short to be
+// indexed, like the "t" of "t-shirt", in the fields it applies to. None
+// when no analyzer of those fields drops any of its words, the indexed
+// words find the phrase then. Only documents with the first indexed word
+// of the phrase get their text checked.
+pub fn spelled_out(index: &Index, text: &str, field: Option<usize>) -> Option<HashSet<u32>> {
+  let fields: Vec<usize> = match field {
+    Some(field) => vec![field],
+    None => (0..index.fields.len()).collect(),
+  };
+  if !fields.iter().any(|x| field_analyzer(index, *x).drops_words(text)) {
+    return None;
+  }
+
+  let mut docs = HashSet::new();
+  for field in fields {
+    let analyzer = field_analyzer(index, field);
+    let terms = analyzer.terms(text);
+    let list = match analyzer.words(text).first() {
+      Some((word, _)) => index.token_scoring.get(&word_key(word)),
+      None => None,
+    };
+    if terms.len() == 0 || list.is_none() {
+      continue;
+    }
+
+    for posting in list.unwrap().iter().filter(|x| x.1 as usize == field) {
+      let value = extract_field(&index.items[&posting.0], &index.fields[field].name);
+      if analyzer.terms(&value).windows(terms.len()).any(|x| x == &terms[..]) {
+        docs.insert(posting.0);
+      }
+    }
+  }
+  return Some(docs);
+}
+
+// Trigrams live next to words in token_scoring, the prefix keeps a trigram
+// like "red" apart from the word "red"
+pub fn gram_key(gram: &str) -> String {
+  format!("#{}", gram)
+}
+
+// The key of a word in token_scoring. A word like "#red", which the
+// whitespace tokenizer keeps, gets a backslash in front so it doesn't
+// collide with a trigram key, and so do words already starting with one.
+fn word_key(word: &str) -> Cow<'_, str> {
+  if word.starts_with('#') || word.starts_with('\\') {
+    return Cow::Owned(format!("\\{}", word));
+  }
+  Cow::Borrowed(word)
+}
+
+// The word a key from word_key stands for
+fn key_word(key: &str) -> &str {
+  if key.starts_with('\\') {
+    return &key[1..];
+  }
+  key
+}
+
+// Expects the document to still be in items
+fn unindex_item(index: &mut Index, iid: u32) {
+  if let Some((words, values)) = index.items.get(&iid).map(|obj| completion_entries(index, obj)) {
+    for word in words {
+      index.completions.remove(&word);
+    }
+    for value in values {
+      index.value_completions.remove(&value);
+    }
+  }
+
+  for term_id in index.doc_tokens.remove(&iid).unwrap_or_default() {
+    index.token_scoring.remove_doc(term_id, iid);
+  }
+
+  if let Some(lengths) = index.doc_lengths.remove(&iid) {
+    for (field, length) in lengths.iter().enumerate() {
+      index.total_lengths[field] -= *length as u64;
+    }
+  }
+  index.dirty.store(true, Ordering::Relaxed);
+}
+
+fn add_posting(index: &mut Index, token: String, posting: (u32, u8, u8), positions: &[u32]) {
+  if let Some(term_id) = index.token_scoring.insert(&token, posting, positions) {
+    index.doc_tokens.entry(posting.0).or_insert_with(Vec::new).push(term_id);
+  }
+}
+
+// Indexes every document again, from scratch
+pub fn reindex(index: &mut Index) {
+  index.token_scoring = Postings::default();
+  index.doc_tokens = HashMap::new();
+  index.doc_lengths = HashMap::new();
+  index.total_lengths = vec![0; index.fields.len()];
+  index.completions = Trie::default();
+  index.value_completions = Trie::default();
+
+  let mut iids: Vec<u32> = index.items.keys().cloned().collect();
+  iids.sort_unstable();
+
+  let items = mem::replace(&mut index.items, HashMap::new());
+  for iid in iids {
+    index_item(index, iid, &items[&iid]);
+  }
+  index.items = items;
+  index.analysis_version = ANALYSIS_VERSION;
+}
+
+// Snapshots from before word positions or the current tokenization
+pub fn needs_reindex(index: &Index) -> bool {
+  index.token_scoring.missing_positions || index.analysis_version < ANALYSIS_VERSION
+}
+
+// Snapshots don't store the per document term lists
+pub fn rebuild_doc_tokens(index: &mut Index) {
+  let mut doc_tokens: HashMap<u32, Vec<u32>> = HashMap::new();
+
+  for (term_id, list) in index.token_scoring.iter() {
+    let mut last = None;
+    for posting in list.iter() {
+      if last != Some(posting.0) {
+        doc_tokens.entry(posting.0).or_insert_with(Vec::new).push(term_id);
+        last = Some(posting.0);
+      }
+    }
+  }
+
+  index.doc_tokens = doc_tokens;
+}
+
+// Field values longer than this aren't completed, they are descriptions
+// rather than names
+const MAX_VALUE_LENGTH: usize = 100;
+
+// The distinct words of a document and, with complete_values, its field
+// values, as they are completed
+fn completion_entries(index: &Index, obj: &Value) -> (Vec<String>, Vec<String>) {
+  let mut words = Vec::new();
+  let mut values = Vec::new();
+  for field in 0..index.fields.len() {
+    let text = extract_field(obj, &index.fields[field].name);
+    let text = text.trim();
+    words.extend(field_analyzer(index, field).surface_words(text));
 
-  for gram in grams {
-    if !index.token_scoring.contains_key(&gram.clone()) {
-      index.token_scoring.insert(gram.to_string(), vec![(iid as u32, 1)]);
-    } else {
-      index.token_scoring.get_mut(&gram).unwrap().push((iid as u32, 1));
+    if index.settings.complete_values && text.len() > 0 && text.chars().count() <= MAX_VALUE_LENGTH {
+      values.push(text.to_string());
     }
   }
 
-  let mut words = clean_words(to_tokenize.to_string());
-  words.sort_unstable();
-  words.dedup();
+  // A document only counts once per entry
+  for entries in [&mut words, &mut values].iter_mut() {
+    let mut seen = HashSet::new();
+    entries.retain(|x| seen.insert(trie::key(x)));
+  }
+  return (words, values);
+}
+
+// Completions aren't stored in snapshots
+pub fn rebuild_completions(index: &mut Index) {
+  let mut completions = Trie::default();
+  let mut value_completions = Trie::default();
+  for obj in index.items.values() {
+    let (words, values) = completion_entries(index, obj);
+    for word in words {
+      completions.add(&word);
+    }
+    for value in values {
+      value_completions.add(&value);
+    }
+  }
+  index.completions = completions;
+  index.value_completions = value_completions;
+}
+
+#[derive(Serialize)]
+pub struct MemoryUsage {
+  pub terms: usize,
+  pub postings: usize,
+  pub documents: usize,
+  pub completions: usize,
+  pub total: usize,
+}
+
+// Heap bytes behind a parsed document, roughly
+fn value_size(value: &Value) -> usize {
+  match value {
+    Value::String(x) => x.capacity(),
+    Value::Array(x) => x.capacity() * mem::size_of::<Value>() + x.iter().map(value_size).sum::<usize>(),
+    Value::Object(x) => x
+      .iter()
+      .map(|(k, v)| k.capacity() + mem::size_of::<(String, Value)>() + value_size(v))
+      .sum::<usize>(),
+    _ => 0,
+  }
+}
+
+// Approximate heap usage in bytes
+pub fn memory_usage(index: &Index) -> MemoryUsage {
+  let (terms, postings) = index.token_scoring.memory_usage();
+
+  let documents = index.items.capacity() * mem::size_of::<(u32, Value)>()
+    + index.items.values().map(value_size).sum::<usize>()
+    + index.id_map.capacity() * mem::size_of::<(String, u32)>()
+    + index.id_map.keys().map(|x| x.capacity()).sum::<usize>()
+    + index.doc_lengths.capacity() * mem::size_of::<(u32, Vec<u32>)>()
+    + index.doc_lengths.values().map(|x| x.capacity() * 4).sum::<usize>()
+    + index.doc_tokens.capacity() * mem::size_of::<(u32, Vec<u32>)>()
+    + index.doc_tokens.values().map(|x| x.capacity() * 4).sum::<usize>();
+
+  let completions = index.completions.memory_usage() + index.value_completions.memory_usage();
+
+  MemoryUsage {
+    terms,
+    postings,
+    documents,
+    completions,
+    total: terms + postings + documents + completions,
+  }
+}
+
+fn index_item(index: &mut Index, iid: u32, obj: &Value) {
+  index.dirty.store(true, Ordering::Relaxed);
 
+  let mut lengths = Vec::new();
+
+  for field in 0..index.fields.len() {
+    let to_tokenize = extract_field(obj, &index.fields[field].name);
+    let to_tokenize = to_tokenize.trim();
+
+    let analyzer = field_analyzer(index, field);
+    let mut grams = analyzer.grams(to_tokenize);
+    grams.sort_unstable();
+    grams.dedup();
+
+    let words = analyzer.words(to_tokenize);
+    drop(analyzer);
+
+    for gram in grams {
+      add_posting(index, gram_key(&gram), (iid, field as u8, 1), &[]);
+    }
+
+    lengths.push(words.len() as u32);
+    index.total_lengths[field] += words.len() as u64;
+
+    let mut positions: BTreeMap<String, Vec<u32>> = BTreeMap::new();
+    for (word, position) in words {
+      positions.entry(word).or_insert_with(Vec::new).push(position);
+    }
+
+    for (word, word_positions) in positions {
+      let tf = cmp::min(word_positions.len(), 255) as u8;
+      add_posting(index, word_key(&word).into_owned(), (iid, field as u8, tf), &word_positions);
+    }
+  }
+
+  index.doc_lengths.insert(iid, lengths);
+
+  let (words, values) = completion_entries(index, obj);
   for word in words {
-    if !index.token_scoring.contains_key(&word.clone()) {
-      index.token_scoring.insert(word.to_string(), vec![(iid as u32, 50)]);
-    } else {
-      index.token_scoring.get_mut(&word).unwrap().push((iid as u32, 50));
+    index.completions.add(&word);
+  }
+  for value in values {
+    index.value_completions.add(&value);
+  }
+}
+
+// BM25F: term frequencies are normalized by field length and weighted by
+// field boost before saturation, the IDF comes from the posting list size
+fn bm25f(index: &Index, doc_freq: usize, postings: &[(u32, u8, u8)], avg_lengths: &Vec<f32>) -> f32 {
+  let num_docs = index.items.len() as f32;
+  let df = doc_freq as f32;
+  let k1 = index.settings.k1;
+  let b = index.settings.b;
+
+  let mut tf = 0.0;
+  for posting in postings {
+    let field = posting.1 as usize;
+    let doc_length = index.doc_lengths
+      .get(&posting.0)
+      .and_then(|x| x.get(field))
+      .map_or(0.0, |x| *x as f32);
+
+    let norm = 1.0 - b + b * doc_length / avg_lengths[field];
+    tf += index.fields[field].boost * posting.2 as f32 / norm;
+  }
+
+  let idf = (1.0 + (num_docs - df + 0.5) / (df + 0.5)).ln();
+  return idf * tf * (k1 + 1.0) / (tf + k1);
+}
+
+// A search result. `matches` holds the char positions the fuzzy match hit
+// in the string built by extract_fields, or those of the words the query
+// matched when the fuzzy match couldn't run.
+#[derive(Clone)]
+pub struct Hit {
+  pub iid: u32,
+  pub score: f32,
+  pub matches: Vec<usize>,
+  // Only for searches that ask for it
+  pub explanation: Option<Explanation>,
+}
+
+// The hits of a search, best first
+pub struct SearchResult {
+  pub hits: Vec<Hit>,
+  // More documents matched than the max_candidates best that were ranked,
+  // the hits only come from those
+  pub truncated: bool,
+  // Documents the query matched that the filter left out before ranking
+  pub filtered_out: usize,
+}
+
+// How the score of a hit came about, see get_key_score_list
+#[derive(Clone, Serialize)]
+pub struct Explanation {
+  // The query tokens found in the document and what each added
+  pub tokens: Vec<TokenScore>,
+  pub phrase_bonus: f32,
+  // Sum of the token scores and the phrase bonus
+  pub token_score: f32,
+  // Candidates with a lower token score were dropped, half the best one
+  pub cutoff: f32,
+  // Token score relative to the best candidate
+  pub relevance: f32,
+  // None when the document wasn't fuzzy matched and is ranked by its
+  // relevance alone
+  pub fuzzy_score: Option<f32>,
+}
+
+#[derive(Clone, Serialize)]
+pub struct TokenScore {
+  pub token: String,
+  // "word" or "ngram"
+  pub kind: &'static str,
+  // Edits between the indexed word and the word in the query
+  pub typos: usize,
+  pub weight: f32,
+  pub score: f32,
+}
+
+#[derive(Serialize)]
+pub struct Highlight {
+  pub field: String,
+  // Matched [start, end) char ranges in the field text
+  pub ranges: Vec<(usize, usize)>,
+  pub snippet: String,
+}
+
+// A query term with its decoded postings, walked in id order
+struct TermCursor {
+  // As it is indexed, for explanations
+  token: String,
+  postings: Vec<Posting>,
+  pos: usize,
+  // Document frequency for words, None for trigrams
+  doc_freq: Option<usize>,
+  // Below 1 for the words of synonyms with several words, which together
+  // count like one, and for words with typos
+  weight: f32,
+  // Edits between the indexed word and the one in the query
+  typos: usize,
+  // Most a single document can get from this term
+  upper_bound: f32,
+}
+
+impl TermCursor {
+  fn current(&self) -> Option<u32> {
+    self.postings.get(self.pos).map(|x| x.0)
+  }
+
+  // Moves to the first posting of `iid` or after, and returns the postings
+  // of `iid` if it has any
+  fn seek(&mut self, iid: u32) -> Option<&[Posting]> {
+    self.pos += self.postings[self.pos..].partition_point(|x| x.0 < iid);
+    let start = self.pos;
+    self.pos += self.postings[start..].partition_point(|x| x.0 == iid);
+    if self.pos == start {
+      return None;
     }
+
+    return Some(&self.postings[start..self.pos]);
   }
 }
 
-fn get_key_score_list(index: &Index, query: String) -> Vec<(u32, f32)> {
-  let mut scores: HashMap<u32, f32> = HashMap::new();
-  let mut query_tokens = gramify(query.clone());
-  
-  for word in clean_words(query.clone()) {
-    query_tokens.push(word.clone());
+// Postings in the given fields, for field:term and for words only some
+// languages analyze the query to
+fn in_fields(postings: Vec<Posting>, fields: &Option<Vec<usize>>) -> Vec<Posting> {
+  match fields {
+    Some(fields) => postings.into_iter().filter(|x| fields.contains(&(x.1 as usize))).collect(),
+    None => postings,
+  }
+}
+
+// None when the fields are all of them
+fn field_set(index: &Index, mut fields: Vec<usize>) -> Option<Vec<usize>> {
+  fields.sort();
+  fields.dedup();
+  if fields.len() == index.fields.len() {
+    return None;
+  }
+  return Some(fields);
+}
+
+// Min heap entry for the best candidates seen so far
+struct Candidate {
+  iid: u32,
+  score: f32,
+  // Found through a word with typos
+  typos: bool,
+}
+
+impl PartialEq for Candidate {
+  fn eq(&self, other: &Candidate) -> bool {
+    self.cmp(other) == cmp::Ordering::Equal
   }
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+  fn partial_cmp(&self, other: &Candidate) -> Option<cmp::Ordering> {
+    Some(self.cmp(other))
+  }
+}
+
+impl Ord for Candidate {
+  fn cmp(&self, other: &Candidate) -> cmp::Ordering {
+    other.score.partial_cmp(&self.score).unwrap_or(cmp::Ordering::Equal)
+  }
+}
+
+// Most indexed words a prefix query expands to, the ones in most documents
+const MAX_PREFIX_TERMS: usize = 50;
+
+fn expand_prefix<'a>(index: &'a Index, prefix: &str) -> Vec<(&'a str, &'a PostingList)> {
+  // Prefixes that were nothing but punctuation
+  if prefix.len() == 0 {
+    return Vec::new();
+  }
+
+  // Keys are sorted, so the words with the prefix follow each other. The
+  // escaped key never starts with '#' and can't reach the trigrams.
+  let prefix = word_key(prefix);
+  let mut lists: Vec<(&str, &PostingList)> = index.token_scoring
+    .terms_from(&prefix)
+    .take_while(|(term, _)| term.starts_with(&*prefix))
+    .map(|(term, list)| (key_word(term), list))
+    .collect();
+  lists.sort_by(|a, b| b.1.doc_freq().cmp(&a.1.doc_freq()));
+  lists.truncate(MAX_PREFIX_TERMS);
+  return lists;
+}
+
+// Most indexed words a word with typos expands to
+const MAX_TYPO_TERMS: usize = 50;
 
-  for token in query_tokens.iter() {
-    if index.token_scoring.contains_key(&token.clone()) {
-      let ids = index.token_scoring.get(token).unwrap();
-      
-      for id in ids.iter() {
-        *scores.entry(id.0).or_insert(0.0) += id.1 as f32;
+// First char after c, None for the last one
+fn next_char(c: char) -> Option<char> {
+  (c as u32 + 1..=char::MAX as u32).filter_map(std::char::from_u32).next()
+}
+
+// Indexed words within the typo budget of word, not word itself, with
+// their number of typos. Fewest typos first, then the words in most
+// documents. The sorted terms are walked with a Levenshtein automaton, once
+// a prefix is too far from word every term starting with it is skipped.
+fn typo_terms<'a>(index: &'a Index, word: &str) -> Vec<(&'a str, &'a PostingList, usize)> {
+  let max = levenshtein::max_typos(word);
+  if max == 0 {
+    return Vec::new();
+  }
+
+  let automaton = Automaton::new(&word_key(word), max);
+  let mut found = Vec::new();
+  // The chars of the last term walked and the states after each of them
+  let mut prefix: Vec<char> = Vec::new();
+  let mut states = vec![automaton.start()];
+  let mut from = String::new();
+  loop {
+    let mut skip_to = None;
+    for (term, list) in index.token_scoring.terms_from(&from) {
+      // Trigram keys all start with '#'
+      if term.starts_with('#') {
+        skip_to = Some(String::from("$"));
+        break;
       }
+
+      let chars: Vec<char> = term.chars().collect();
+      let common = prefix.iter().zip(chars.iter()).take_while(|(a, b)| a == b).count();
+      prefix.truncate(common);
+      states.truncate(common + 1);
+
+      for c in chars[common..].iter() {
+        let state = automaton.step(states.last().unwrap(), *c);
+        if !automaton.can_match(&state) {
+          skip_to = next_char(*c).map(|next| prefix.iter().chain(Some(&next)).collect());
+          break;
+        }
+        prefix.push(*c);
+        states.push(state);
+      }
+      if skip_to.is_some() {
+        break;
+      }
+
+      if prefix.len() == chars.len() {
+        match automaton.distance(states.last().unwrap()) {
+          Some(typos) if typos > 0 => found.push((key_word(term), list, typos)),
+          _ => {}
+        }
+      }
+    }
+
+    match skip_to {
+      Some(next) => from = next,
+      None => break,
     }
   }
 
-  let mut key_score_list: Vec<(u32, f32)> = Vec::new();
-  for (id, score) in scores {
-    key_score_list.push((id, score));
+  found.sort_by(|a, b| a.2.cmp(&b.2).then(b.1.doc_freq().cmp(&a.1.doc_freq())));
+  found.truncate(MAX_TYPO_TERMS);
+  return found;
+}
+
+fn idf(index: &Index, doc_freq: usize) -> f32 {
+  let num_docs = index.items.len() as f32;
+  let df = doc_freq as f32;
+  (1.0 + (num_docs - df + 0.5) / (df + 0.5)).ln()
+}
+
+fn word_term(index: &Index, word: &str, list: &PostingList, fields: &Option<Vec<usize>>, weight: f32) -> TermCursor {
+  // BM25 saturates at idf * (k1 + 1)
+  TermCursor {
+    token: word.to_string(),
+    postings: in_fields(list.to_vec(), fields),
+    pos: 0,
+    doc_freq: Some(list.doc_freq()),
+    weight,
+    typos: 0,
+    upper_bound: weight * WORD_WEIGHT * idf(index, list.doc_freq()) * (index.settings.k1 + 1.0),
   }
+}
+
+// A word found through typos counts less than the word as typed would,
+// even when it is rarer
+fn typo_term(index: &Index, word: &str, list: &PostingList, exact: Option<&PostingList>, fields: &Option<Vec<usize>>, typos: usize) -> TermCursor {
+  let mut weight = TYPO_WEIGHT.powi(typos as i32);
+  if let Some(exact) = exact {
+    weight *= (idf(index, exact.doc_freq()) / idf(index, list.doc_freq())).min(1.0);
+  }
+  let mut term = word_term(index, word, list, fields, weight);
+  term.typos = typos;
+  return term;
+}
+
+// Adds `fields` to the fields of `token`, keeping tokens in the order
+// they're first seen
+fn add_token(tokens: &mut Vec<(String, Vec<usize>)>, token: String, fields: &Vec<usize>) {
+  match tokens.iter_mut().find(|x| x.0 == token) {
+    Some(existing) => existing.1.extend(fields.iter()),
+    None => tokens.push((token, fields.clone())),
+  }
+}
+
+// The trigrams and words of the text in the query that adds to the score.
+// Text scoped to a field is tokenized on its own, other text once per
+// analyzer of the fields.
+fn query_terms(index: &Index, query: &Query) -> Vec<TermCursor> {
+  let mut scopes: Vec<(Option<usize>, String)> = Vec::new();
+  let mut prefixes = Vec::new();
+
+  for leaf in query.positive_leaves() {
+    let (text, field) = match leaf {
+      Node::Term { text, field, .. } | Node::Phrase { text, field, .. } => (text, *field),
+      Node::Prefix { words, .. } => {
+        prefixes.extend(words.iter());
+        continue;
+      }
+      _ => continue,
+    };
+
+    match scopes.iter_mut().find(|x| x.0 == field) {
+      Some(scope) => {
+        scope.1.push(' ');
+        scope.1.push_str(text);
+      }
+      None => scopes.push((field, text.clone())),
+    }
+  }
+
+  let mut terms = Vec::new();
+
+  for (field, text) in scopes {
+    let text = text.split_whitespace().collect::<Vec<&str>>().join(" ");
+    let groups = match field {
+      Some(field) => vec![(field_analyzer(index, field), vec![field])],
+      None => analyzers(index),
+    };
+
+    let mut grams = Vec::new();
+    let mut words = Vec::new();
+    for (analyzer, fields) in groups.iter() {
+      for gram in analyzer.grams(&text) {
+        add_token(&mut grams, gram, fields);
+      }
+      for (word, _) in analyzer.words(&text) {
+        add_token(&mut words, word, fields);
+      }
+    }
+
+    for (gram, fields) in grams {
+      if let Some(list) = index.token_scoring.get(&gram_key(&gram)) {
+        // An n-gram counts once per document, with the boost of the best
+        // field it occurs in
+        let max_boost = fields.iter().map(|x| index.fields[*x].boost).fold(0.0, f32::max);
+        terms.push(TermCursor {
+          token: gram,
+          postings: in_fields(list.to_vec(), &field_set(index, fields)),
+          pos: 0,
+          doc_freq: None,
+          weight: 1.0,
+          typos: 0,
+          upper_bound: GRAM_WEIGHT * max_boost,
+        });
+      }
+    }
+
+    for (word, fields) in words {
+      let fields = field_set(index, fields);
+      let exact = index.token_scoring.get(&word_key(&word));
+      if let Some(list) = exact {
+        terms.push(word_term(index, &word, list, &fields, 1.0));
+      }
+      for (typo, list, typos) in typo_terms(index, &word) {
+        terms.push(typo_term(index, typo, list, exact, &fields, typos));
+      }
+    }
+  }
+
+  for prefix in prefixes {
+    for (word, list) in expand_prefix(index, &prefix.words[0]) {
+      terms.push(word_term(index, word, list, &prefix.fields, 1.0));
+    }
+  }
+
+  // Synonyms find documents through their words only, a phrase counts
+  // like a single word so documents with the terms as typed still rank
+  // above it
+  for leaf in query.synonym_leaves() {
+    let (leaf, docs) = match leaf {
+      Node::Within(phrase, docs) => (phrase.as_ref(), Some(docs)),
+      leaf => (leaf, None),
+    };
+    if let Node::Term { words, .. } = leaf {
+      for variant in words {
+        let weight = 1.0 / cmp::max(1, variant.words.len()) as f32;
+        for word in variant.words.iter() {
+          if let Some(list) = index.token_scoring.get(&word_key(word)) {
+            let mut term = word_term(index, word, list, &variant.fields, weight);
+            if let Some(docs) = docs {
+              term.postings.retain(|x| docs.contains(&x.0));
+            }
+            terms.push(term);
+          }
+        }
+      }
+    }
+  }
+
+  return terms;
+}
+
+// The words of a phrase with their decoded posting lists, walked in id
+// order like the query terms
+struct PhraseCursor {
+  words: Vec<(DecodedList, usize)>,
+  // Position of every word relative to the first, stop words in between
+  // leave gaps
+  offsets: Vec<u32>,
+  slop: Option<u32>,
+  fields: Option<Vec<usize>>,
+}
+
+// Fewest extra positions the words span in one field, None if they don't
+// occur in order at their offsets and `exact` is set, or a word has no
+// positions at all
+fn phrase_distance(positions: &Vec<&[u32]>, offsets: &Vec<u32>, exact: bool) -> Option<u32> {
+  if positions.len() == 0 || positions.iter().any(|x| x.len() == 0) {
+    return None;
+  }
+
+  if exact {
+    let found = positions[0].iter().any(|start| {
+      positions
+        .iter()
+        .zip(offsets.iter())
+        .all(|(x, offset)| x.binary_search(&(start + offset)).is_ok())
+    });
+    return if found { Some(0) } else { None };
+  }
+
+  // Smallest window holding every word, moving the lowest one forward
+  let span = offsets.iter().max().unwrap() + 1;
+  let mut next = vec![0; positions.len()];
+  let mut smallest = u32::max_value();
+  loop {
+    let current: Vec<u32> = positions.iter().zip(next.iter()).map(|(x, i)| x[*i]).collect();
+    let low = (0..current.len()).min_by_key(|i| current[*i]).unwrap();
+    let width = current.iter().max().unwrap() - current[low] + 1;
+    smallest = cmp::min(smallest, width.saturating_sub(span));
+
+    next[low] += 1;
+    if next[low] >= positions[low].len() {
+      return Some(smallest);
+    }
+  }
+}
+
+impl PhraseCursor {
+  // Bonus for the best field the phrase occurs in, None if it doesn't
+  // occur in the document
+  fn score(&mut self, index: &Index, iid: u32) -> Option<f32> {
+    let mut ranges = Vec::new();
+    for (list, pos) in self.words.iter_mut() {
+      *pos += list.postings[*pos..].partition_point(|x| x.0 < iid);
+      let end = *pos + list.postings[*pos..].partition_point(|x| x.0 == iid);
+      if end == *pos {
+        return None;
+      }
+      ranges.push((*pos, end));
+    }
+
+    let mut best: Option<f32> = None;
+    for field in 0..index.fields.len() {
+      if self.fields.as_ref().map_or(false, |x| !x.contains(&field)) {
+        continue;
+      }
+
+      let positions: Option<Vec<&[u32]>> = self.words
+        .iter()
+        .zip(ranges.iter())
+        .map(|((list, _), range)| {
+          (range.0..range.1)
+            .find(|i| list.postings[*i].1 as usize == field)
+            .map(|i| list.positions_of(i))
+        })
+        .collect();
+      if positions.is_none() {
+        continue;
+      }
+
+      let distance = phrase_distance(&positions.unwrap(), &self.offsets, self.slop.is_none());
+      if let Some(distance) = distance.filter(|x| *x <= self.slop.unwrap_or(0)) {
+        let bonus = PHRASE_WEIGHT * index.fields[field].boost / (1.0 + distance as f32);
+        best = Some(best.map_or(bonus, |x| x.max(bonus)));
+      }
+    }
+    return best;
+  }
+}
+
+// The query tree with the posting lists its leaves need to decide whether
+// a document matches, walked in id order
+enum Matcher {
+  // Terms without indexed words
+  Always,
+  // In any of the lists, in the fields if given. Empty for words that
+  // aren't indexed at all.
+  Any(Vec<(DecodedList, usize)>, Option<Vec<usize>>),
+  Phrase(PhraseCursor),
+  Group(Vec<(Occur, Matcher)>),
+  Or(Vec<Matcher>),
+  // Only in the documents of the set, see Node::Within
+  Within(Box<Matcher>, HashSet<u32>),
+}
+
+fn phrase_matcher(index: &Index, words: &Words, slop: Option<u32>) -> Matcher {
+  let mut lists = Vec::new();
+  for word in words.words.iter() {
+    match index.token_scoring.get(&word_key(word)) {
+      Some(list) => lists.push((list.decode(), 0)),
+      // No document can match a phrase with an unknown word
+      None => return Matcher::Any(Vec::new(), None),
+    }
+  }
+  let offsets = words.positions.iter().map(|x| x - words.positions[0]).collect();
+  Matcher::Phrase(PhraseCursor { words: lists, offsets, slop, fields: words.fields.clone() })
+}
+
+fn term_matcher(index: &Index, words: &Words) -> Matcher {
+  if words.words.len() == 0 {
+    return Matcher::Always;
+  }
+  if words.words.len() > 1 {
+    return phrase_matcher(index, words, None);
+  }
+  // The word as it is and the indexed words a few typos away from it
+  let word = &words.words[0];
+  let lists = index.token_scoring
+    .get(&word_key(word))
+    .into_iter()
+    .chain(typo_terms(index, word).into_iter().map(|x| x.1))
+    .map(|x| (x.decode(), 0))
+    .collect();
+  Matcher::Any(lists, words.fields.clone())
+}
+
+// A term matches in any of the languages it was analyzed for
+fn any_variant(mut matchers: Vec<Matcher>) -> Matcher {
+  if matchers.len() == 1 {
+    return matchers.pop().unwrap();
+  }
+  if matchers.len() == 0 {
+    return Matcher::Always;
+  }
+  Matcher::Or(matchers)
+}
+
+fn build_matcher(index: &Index, node: &Node) -> Matcher {
+  match node {
+    Node::Term { words, .. } => any_variant(words.iter().map(|x| term_matcher(index, x)).collect()),
+    Node::Prefix { words, .. } => any_variant(words.iter().map(|x| {
+      let lists = expand_prefix(index, &x.words[0]).into_iter().map(|x| (x.1.decode(), 0)).collect();
+      Matcher::Any(lists, x.fields.clone())
+    }).collect()),
+    Node::Phrase { words, slop, .. } => any_variant(words.iter().map(|x| phrase_matcher(index, x, *slop)).collect()),
+    Node::Group(clauses) => Matcher::Group(
+      clauses.iter().map(|(occur, x)| (*occur, build_matcher(index, x))).collect()
+    ),
+    Node::Or(branches) => Matcher::Or(branches.iter().map(|x| build_matcher(index, x)).collect()),
+    Node::Synonyms(original, phrases) => Matcher::Or(
+      Some(original.as_ref()).into_iter().chain(phrases.iter()).map(|x| build_matcher(index, x)).collect()
+    ),
+    Node::Within(phrase, docs) => Matcher::Within(Box::new(build_matcher(index, phrase)), docs.clone()),
+  }
+}
+
+impl Matcher {
+  // In a strict group at least one clause has to match when none is
+  // required. The top level isn't strict, there words only rank documents
+  // and trigrams still find the ones with typos.
+  fn matches(&mut self, index: &Index, iid: u32, strict: bool) -> bool {
+    match self {
+      Matcher::Always => true,
+      Matcher::Any(lists, fields) => lists.iter_mut().any(|(list, pos)| {
+        *pos += list.postings[*pos..].partition_point(|x| x.0 < iid);
+        list.postings[*pos..]
+          .iter()
+          .take_while(|x| x.0 == iid)
+          .any(|x| fields.as_ref().map_or(true, |fields| fields.contains(&(x.1 as usize))))
+      }),
+      Matcher::Phrase(phrase) => phrase.score(index, iid).is_some(),
+      Matcher::Group(clauses) => {
+        let mut has_must = false;
+        let mut any_should = false;
+        for (occur, clause) in clauses.iter_mut() {
+          match occur {
+            Occur::Must => {
+              has_must = true;
+              if !clause.matches(index, iid, true) {
+                return false;
+              }
+            }
+            Occur::MustNot => {
+              if clause.matches(index, iid, true) {
+                return false;
+              }
+            }
+            Occur::Should => {
+              if strict && !has_must && !any_should {
+                any_should = clause.matches(index, iid, true);
+              }
+            }
+          }
+        }
+        let has_should = clauses.iter().any(|x| x.0 == Occur::Should);
+        !strict || has_must || !has_should || any_should
+      }
+      Matcher::Or(branches) => branches.iter_mut().any(|x| x.matches(index, iid, strict)),
+      Matcher::Within(matcher, docs) => docs.contains(&iid) && matcher.matches(index, iid, strict),
+    }
+  }
+
+  // Sum of the bonuses of the phrases that aren't excluded
+  fn phrase_bonus(&mut self, index: &Index, iid: u32) -> f32 {
+    match self {
+      Matcher::Phrase(phrase) => phrase.score(index, iid).unwrap_or(0.0),
+      Matcher::Group(clauses) => clauses
+        .iter_mut()
+        .filter(|x| x.0 != Occur::MustNot)
+        .map(|x| x.1.phrase_bonus(index, iid))
+        .sum(),
+      Matcher::Or(branches) => branches.iter_mut().map(|x| x.phrase_bonus(index, iid)).sum(),
+      Matcher::Within(matcher, docs) if docs.contains(&iid) => matcher.phrase_bonus(index, iid),
+      _ => 0.0,
+    }
+  }
+}
+
+// Words per field on average
+fn avg_lengths(index: &Index) -> Vec<f32> {
+  let num_docs = cmp::max(1, index.doc_lengths.len()) as f32;
+  return index.total_lengths
+    .iter()
+    .map(|x| (*x as f32 / num_docs).max(1.0))
+    .collect();
+}
+
+// What a term adds to the score of a document with the given postings
+fn term_score(index: &Index, doc_freq: Option<usize>, weight: f32, postings: &[Posting], avg_lengths: &Vec<f32>) -> f32 {
+  match doc_freq {
+    Some(df) => weight * WORD_WEIGHT * bm25f(index, df, postings, avg_lengths),
+    None => {
+      let boost = postings
+        .iter()
+        .map(|x| index.fields[x.1 as usize].boost)
+        .fold(0.0, f32::max);
+      GRAM_WEIGHT * boost
+    }
+  }
+}
+
+// Token scores of the best `max_candidates` documents, or of all of them
+// when `uncapped`, best first, along with whether the limit left any out
+// and how many `keep` did.
+// Documents are visited in id order across all posting lists (MaxScore):
+// once a document can no longer make it into the top K, or under half the
+// best score so far, lists whose combined upper bounds can't reach that
+// threshold only get looked into for documents found through the others.
+// Documents have to match the query tree, see Matcher::matches, and be
+// kept, so a filter doesn't take up places in the top K.
+fn top_candidates(index: &Index, query: &Query, keep: &dyn Fn(&Value) -> bool, uncapped: bool) -> (Vec<(u32, f32, bool)>, bool, usize) {
+  let mut terms = query_terms(index, query);
+  let mut matcher = build_matcher(index, &query.root);
+  let max_candidates = if uncapped { usize::MAX } else { cmp::max(1, index.settings.max_candidates) };
+
+  let max_boost = index.fields.iter().map(|x| x.boost).fold(0.0, f32::max);
+  // Every language a phrase was analyzed for can add a bonus
+  let leaves = query.positive_leaves().into_iter().chain(query.synonym_leaves());
+  let num_phrases: usize = leaves.map(|x| match x {
+    Node::Within(phrase, _) => phrase.as_ref(),
+    x => x,
+  }).map(|x| match x {
+    Node::Phrase { words, .. } => words.len(),
+    Node::Term { words, .. } => words.iter().filter(|x| x.words.len() > 1).count(),
+    _ => 0,
+  }).sum();
+  let phrase_bound = PHRASE_WEIGHT * max_boost * num_phrases as f32;
+
+  let avg_lengths = avg_lengths(index);
+
+  // Term positions by upper bound, lowest first
+  let mut by_bound: Vec<usize> = (0..terms.len()).collect();
+  by_bound.sort_by(|a, b| terms[*a].upper_bound.partial_cmp(&terms[*b].upper_bound).unwrap());
+
+  let mut heap: BinaryHeap<Candidate> = BinaryHeap::new();
+  let mut best = 0.0;
+  let mut truncated = false;
+  let mut filtered_out = 0;
+
+  loop {
+    let full = heap.len() >= max_candidates;
+    let mut threshold = best / 2.0;
+    if full {
+      threshold = heap.peek().unwrap().score.max(threshold);
+    }
+
+    // Skip the lowest bound terms as long as together they stay under the
+    // threshold, a document has to be in one of the remaining lists
+    let mut bound_sum = phrase_bound;
+    let mut essential = 0;
+    while essential < by_bound.len() && bound_sum + terms[by_bound[essential]].upper_bound < threshold {
+      bound_sum += terms[by_bound[essential]].upper_bound;
+      essential += 1;
+    }
+    // Documents skipped only because the top K is full might have been
+    // above the cutoff
+    if full && essential > 0 && threshold > best / 2.0 {
+      truncated = true;
+    }
+
+    let next = by_bound[essential..]
+      .iter()
+      .filter_map(|x| terms[*x].current())
+      .min();
+    if next.is_none() {
+      break;
+    }
+    let iid = next.unwrap();
+
+    // Summed in query order, so scores don't depend on the pruning
+    let mut score = 0.0;
+    let mut typos = false;
+    for term in terms.iter_mut() {
+      let doc_freq = term.doc_freq;
+      let weight = term.weight;
+      let has_typos = term.typos > 0;
+      if let Some(group) = term.seek(iid) {
+        typos = typos || has_typos;
+        score += term_score(index, doc_freq, weight, group, &avg_lengths);
+      }
+    }
+
+    if !matcher.matches(index, iid, false) {
+      continue;
+    }
+    if !keep(&index.items[&iid]) {
+      filtered_out += 1;
+      continue;
+    }
+    score += matcher.phrase_bonus(index, iid);
+
+    if score > best {
+      best = score;
+    }
+    if heap.len() < max_candidates {
+      heap.push(Candidate { iid, score, typos });
+      continue;
+    }
+    // One of them has to go, which matters above the cutoff
+    if score.min(heap.peek().unwrap().score) >= best / 2.0 {
+      truncated = true;
+    }
+    if score > heap.peek().unwrap().score {
+      heap.pop();
+      heap.push(Candidate { iid, score, typos });
+    }
+  }
+
+  // into_sorted_vec is ascending by Ord, which is by descending score
+  let candidates = heap
+    .into_sorted_vec()
+    .into_iter()
+    .map(|x| (x.iid, x.score, x.typos))
+    .collect();
+  return (candidates, truncated, filtered_out);
+}
+
+// Adds the tokens and phrase bonus behind their token score to the
+// explanations of hits, going through the documents in id order like
+// top_candidates does
+fn explain_tokens(index: &Index, query: &Query, hits: &mut Vec<Hit>) {
+  let mut terms = query_terms(index, query);
+  let mut matcher = build_matcher(index, &query.root);
+  let avg_lengths = avg_lengths(index);
+
+  let mut order: Vec<&mut Hit> = hits.iter_mut().collect();
+  order.sort_by_key(|x| x.iid);
+  for hit in order {
+    let explanation = hit.explanation.as_mut().unwrap();
+    for term in terms.iter_mut() {
+      let doc_freq = term.doc_freq;
+      let weight = term.weight;
+      let typos = term.typos;
+      let token = term.token.clone();
+      if let Some(group) = term.seek(hit.iid) {
+        explanation.tokens.push(TokenScore {
+          token,
+          kind: if doc_freq.is_some() { "word" } else { "ngram" },
+          typos,
+          weight,
+          score: term_score(index, doc_freq, weight, group, &avg_lengths),
+        });
+      }
+    }
+
+    explanation.phrase_bonus = matcher.phrase_bonus(index, hit.iid);
+  }
+}
+
+// Matches hits the fuzzy match didn't run on to the chars of the indexed
+// words their document was found through, going through the documents in
+// id order like top_candidates does
+fn match_terms(index: &Index, query: &Query, hits: &mut Vec<Hit>) {
+  let mut terms: Vec<TermCursor> = query_terms(index, query)
+    .into_iter()
+    .filter(|x| x.doc_freq.is_some())
+    .collect();
+
+  let mut order: Vec<&mut Hit> = hits.iter_mut().filter(|x| x.matches.len() == 0).collect();
+  order.sort_by_key(|x| x.iid);
+  for hit in order {
+    // Fields and the words found in them
+    let mut found: Vec<(usize, String)> = Vec::new();
+    for term in terms.iter_mut() {
+      let token = term.token.clone();
+      if let Some(group) = term.seek(hit.iid) {
+        found.extend(group.iter().map(|x| (x.1 as usize, token.clone())));
+      }
+    }
+
+    let value = index.items.get(&hit.iid).unwrap();
+    let mut offset = 0;
+    for (i, field) in index.fields.iter().enumerate() {
+      let text = extract_field(value, &field.name);
+      if found.iter().any(|x| x.0 == i) {
+        let spans = field_analyzer(index, i).word_spans(&text).unwrap_or_default();
+        for (word, start, end) in spans {
+          if found.iter().any(|x| x.0 == i && x.1 == word) {
+            hit.matches.extend(offset + start..offset + end);
+          }
+        }
+      }
+      offset += text.chars().count();
+    }
+    hit.matches.sort();
+    hit.matches.dedup();
+  }
+}
+
+// Positions matched in folded text as positions in the text it was folded
+// from, a char folded to several counts once
+fn original_positions(matches: &Vec<usize>, origins: &Vec<usize>) -> Vec<usize> {
+  let mut positions: Vec<usize> = matches.iter().map(|x| origins[*x]).collect();
+  positions.dedup();
+  return positions;
+}
+
+fn get_key_score_list(index: &Index, query: &Query, explain: bool, keep: &dyn Fn(&Value) -> bool, uncapped: bool) -> SearchResult {
+  let (mut key_score_list, truncated, filtered_out) = top_candidates(index, query, keep, uncapped);
 
   if key_score_list.len() == 0 {
-    return Vec::new();
+    return SearchResult { hits: Vec::new(), truncated, filtered_out };
   }
 
-  key_score_list.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
   let highest = key_score_list[0].1;
-  key_score_list.retain(|x| x.1 >= highest / 2.0);
+  let cutoff = highest / 2.0;
+  key_score_list.retain(|x| x.1 >= cutoff);
 
   println!("{} candidates", key_score_list.len());
 
-  let mut fuzzy_scores: Vec<(u32, f32)> = Vec::new();
+  // The fuzzy match only works on single byte chars in the pattern,
+  // documents that can't be fuzzy matched are ranked by their token score
+  let (pattern, _) = fold_chars(&query.text);
+  let fuzzy = pattern.is_ascii();
+
+  let mut fuzzy_scores: Vec<Hit> = Vec::new();
   for tuple in key_score_list.iter_mut() {
     let id = tuple.0;
-    let item = index.items.get(&id).unwrap().clone();
-    let value = parse_json(item);
-    let super_string = extract_fields(&value, &index.fields);
+    let value = index.items.get(&id).unwrap();
 
-    let mut search = FuzzySearch::new(&query, &super_string, true);
-    let fuzzy_match = search.best_match();
+    let mut fuzzy_match = None;
+    let mut origins = Vec::new();
+    if fuzzy {
+      let (super_string, x) = fold_chars(&extract_fields(value, &index.fields));
+      let mut search = FuzzySearch::new(&pattern, &super_string, true);
+      fuzzy_match = search.best_match();
+      origins = x;
+    }
+
+    let relevance = tuple.1 / highest;
+    let explanation = |fuzzy_score| {
+      if !explain {
+        return None;
+      }
+      Some(Explanation {
+        tokens: Vec::new(),
+        phrase_bonus: 0.0,
+        token_score: tuple.1,
+        cutoff,
+        relevance,
+        fuzzy_score,
+      })
+    };
     
     if fuzzy_match.is_some() {
-      let score = fuzzy_match.unwrap().score() as f32;
-      fuzzy_scores.push(
-        (id.clone(), score)
-      );
+      // The fuzzy match decides the order, weighted by how the token
+      // score compares to the best candidate
+      let fuzzy_match = fuzzy_match.unwrap();
+      let fuzzy_score = (fuzzy_match.score() as f32).max(0.0);
+      fuzzy_scores.push(Hit {
+        iid: id,
+        score: fuzzy_score * relevance,
+        matches: original_positions(fuzzy_match.matches(), &origins),
+        explanation: explanation(Some(fuzzy_score)),
+      });
+    }
+    else if !fuzzy || !query.is_plain() || tuple.2 {
+      // The query tree already decided this document matches, words out
+      // of order, from one side of an OR or with typos just can't be
+      // fuzzy matched
+      fuzzy_scores.push(Hit {
+        iid: id,
+        score: relevance,
+        matches: Vec::new(),
+        explanation: explanation(None),
+      });
     }
   }
 
-  fuzzy_scores.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap());
-  let highest = key_score_list[0].1;
-  key_score_list.retain(|x| x.1 >= highest / 4.0);
+  match_terms(index, query, &mut fuzzy_scores);
+  if explain {
+    explain_tokens(index, query, &mut fuzzy_scores);
+  }
 
-  return fuzzy_scores;
+  fuzzy_scores.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+
+  return SearchResult { hits: fuzzy_scores, truncated, filtered_out };
 }
 
-pub fn search(index: &Index, original_query: String) -> Vec<String> {
+// An indexed word is only corrected to one that is in this many times more
+// documents
+const SUGGEST_RATIO: usize = 10;
+
+// The indexed word closest to term, in the most documents when several
+// are as close. Words that are indexed themselves only get a correction
+// that is far more common.
+fn correct_word(index: &Index, term: &str) -> Option<(String, Posting)> {
+  let doc_freq = index.token_scoring.get(&word_key(term)).map_or(0, |x| x.doc_freq());
+  let (candidate, list, _) = typo_terms(index, term)
+    .into_iter()
+    .find(|x| x.1.doc_freq() >= cmp::max(doc_freq * SUGGEST_RATIO, 1))?;
+  return Some((candidate.to_string(), list.iter().next()?));
+}
+
+// The query with its misspelled words replaced by indexed ones, None when
+// there is nothing to correct. Field names, prefixes and the rest of the
+// syntax are kept as they are.
+pub fn suggest(index: &Index, query: &str) -> Option<String> {
+  let analyzer = match analyzers(index).into_iter().next() {
+    Some((analyzer, _)) => analyzer,
+    None => return None,
+  };
+
+  let chars: Vec<char> = query.chars().collect();
+  let mut suggestion = String::new();
+  let mut corrected = false;
+  let mut i = 0;
+  while i < chars.len() {
+    if !chars[i].is_alphanumeric() {
+      suggestion.push(chars[i]);
+      i += 1;
+      continue;
+    }
+
+    let start = i;
+    while i < chars.len() && chars[i].is_alphanumeric() {
+      i += 1;
+    }
+    let word: String = chars[start..i].iter().collect();
+
+    // Field names and prefixes
+    if chars.get(i).map_or(false, |c| *c == ':' || *c == '*') {
+      suggestion.push_str(&word);
+      continue;
+    }
+
+    let words = analyzer.words(&word);
+    let correction = match words.len() {
+      1 => correct_word(index, &words[0].0),
+      _ => None,
+    };
+
+    match correction {
+      Some((term, posting)) => {
+        let text = extract_field(&index.items[&posting.0], &index.fields[posting.1 as usize].name);
+        let spelling = field_analyzer(index, posting.1 as usize).spelling(&text, &term);
+        suggestion.push_str(&spelling.unwrap_or(term));
+        corrected = true;
+      }
+      None => suggestion.push_str(&word),
+    }
+  }
+
+  if !corrected {
+    return None;
+  }
+  return Some(suggestion);
+}
+
+// Fails on syntax errors in the query, see query::parse. Only documents
+// `keep` accepts are ranked, the max_candidates best of them or all of
+// them when `uncapped`. Hits come with an explanation of their score when
+// `explain` is set.
+pub fn search(index: &Index, original_query: String, explain: bool, keep: &dyn Fn(&Value) -> bool, uncapped: bool) -> Result<SearchResult, String> {
   let query = original_query.trim();
 
   if query.len() == 0 {
-    let mut vec: Vec<String> = Vec::new();
-    for item in index.items.values() {
-      vec.push(item.clone());
+    let mut vec: Vec<Hit> = Vec::new();
+    let mut filtered_out = 0;
+    for (iid, obj) in index.items.iter() {
+      if !keep(obj) {
+        filtered_out += 1;
+        continue;
+      }
+      vec.push(Hit { iid: *iid, score: 0.0, matches: Vec::new(), explanation: None });
     }
-    return vec;
+    return Ok(SearchResult { hits: vec, truncated: false, filtered_out });
   }
-  
-  let mut key_score_list = get_key_score_list(&index, query.to_string());
-  
-  let mut real_items : Vec<String> = vec![];
-  for tuple in key_score_list.iter_mut() {
-    let item = index.items.get(&tuple.0).unwrap().clone();
-    real_items.push(item);
+
+  let parsed = query::parse(query, index)?;
+
+  // Nothing to rank by, like -red: every document the query doesn't
+  // exclude, unranked as without a query
+  if parsed.positive_leaves().len() == 0 {
+    let mut iids: Vec<u32> = index.items.keys().cloned().collect();
+    iids.sort_unstable();
+
+    let mut matcher = build_matcher(index, &parsed.root);
+    let mut vec: Vec<Hit> = Vec::new();
+    let mut filtered_out = 0;
+    for iid in iids {
+      if !matcher.matches(index, iid, false) {
+        continue;
+      }
+      if !keep(&index.items[&iid]) {
+        filtered_out += 1;
+        continue;
+      }
+      vec.push(Hit { iid, score: 0.0, matches: Vec::new(), explanation: None });
+    }
+    return Ok(SearchResult { hits: vec, truncated: false, filtered_out });
+  }
+
+  return Ok(get_key_score_list(&index, &parsed, explain, keep, uncapped));
+}
+
+// Wraps the char ranges of a field in tags. Long fields are cut down to
+// `max_length` chars around the first match.
+fn render_snippet(text: &str, ranges: &Vec<(usize, usize)>, pre_tag: &str, post_tag: &str, max_length: usize) -> String {
+  let chars: Vec<char> = text.chars().collect();
+
+  let mut start = 0;
+  let mut end = chars.len();
+  if chars.len() > max_length {
+    let first = ranges.first().map_or(0, |x| x.0);
+    start = first.saturating_sub(max_length / 4);
+    end = cmp::min(chars.len(), start + max_length);
   }
 
-  return real_items;
+  let mut snippet = String::new();
+  if start > 0 {
+    snippet.push_str("…");
+  }
+
+  let mut pos = start;
+  for range in ranges.iter() {
+    if range.1 <= start || range.0 >= end {
+      continue;
+    }
+    let from = cmp::max(range.0, start);
+    let to = cmp::min(range.1, end);
+    snippet.extend(&chars[pos..from]);
+    snippet.push_str(pre_tag);
+    snippet.extend(&chars[from..to]);
+    snippet.push_str(post_tag);
+    pos = to;
+  }
+  snippet.extend(&chars[pos..end]);
+
+  if end < chars.len() {
+    snippet.push_str("…");
+  }
+  return snippet;
+}
+
+// Maps the matched positions of a hit back onto the fields they came from
+pub fn highlight(index: &Index, obj: &Value, hit: &Hit, fields: Option<&Vec<String>>, pre_tag: &str, post_tag: &str, max_length: usize) -> Vec<Highlight> {
+  let mut highlights = Vec::new();
+  let mut offset = 0;
+
+  for field in index.fields.iter() {
+    let text = extract_field(obj, &field.name);
+    let length = text.chars().count();
+
+    let mut ranges: Vec<(usize, usize)> = Vec::new();
+    for position in hit.matches.iter() {
+      if *position < offset || *position >= offset + length {
+        continue;
+      }
+      let position = position - offset;
+      match ranges.last_mut() {
+        Some(last) if last.1 == position => last.1 += 1,
+        _ => ranges.push((position, position + 1)),
+      }
+    }
+    offset += length;
+
+    if ranges.len() == 0 || fields.map_or(false, |x| !x.contains(&field.name)) {
+      continue;
+    }
+
+    // extract_field separates values with spaces, which the match can
+    // include but the snippet should not end with
+    let text = text.trim_end();
+    let text_length = text.chars().count();
+    ranges.retain(|x| x.0 < text_length);
+    for range in ranges.iter_mut() {
+      range.1 = cmp::min(range.1, text_length);
+    }
+
+    highlights.push(Highlight {
+      field: field.name.clone(),
+      snippet: render_snippet(text, &ranges, pre_tag, post_tag, max_length),
+      ranges,
+    });
+  }
+
+  return highlights;
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  // A field keeping punctuation, so words can start with '#'
+  fn tags_index() -> Index {
+    let mut settings = Settings::default();
+    settings.analyzers = serde_json::from_value(json!({"tags": {"tokenizer": "whitespace"}})).unwrap();
+    let fields: Vec<Field> = serde_json::from_value(json!([{"name": "tags", "analyzer": "tags"}])).unwrap();
+    let mut index = create(fields, settings);
+    let items = vec![
+      json!({"_id": "1", "tags": "#tag foo"}),
+      json!({"_id": "2", "tags": "tag bar"}),
+      json!({"_id": "3", "tags": "\\tag baz"}),
+    ];
+    for item in items {
+      add_object(&mut index, item, OnConflict::Reject).unwrap();
+    }
+    return index;
+  }
+
+  fn ids(index: &Index, query: &str) -> Vec<String> {
+    let mut ids: Vec<String> = search(index, query.to_string(), false, &|_| true, false)
+      .unwrap()
+      .hits
+      .iter()
+      .map(|x| index.items[&x.iid]["_id"].as_str().unwrap().to_string())
+      .collect();
+    ids.sort();
+    return ids;
+  }
+
+  #[test]
+  fn words_starting_with_hash_are_not_trigrams() {
+    let index = tags_index();
+    assert!(index.token_scoring.get("#tag").unwrap().iter().all(|x| x.2 == 1));
+    assert_eq!(index.token_scoring.get("\\#tag").unwrap().doc_freq(), 1);
+    assert_eq!(index.token_scoring.get("\\\\tag").unwrap().doc_freq(), 1);
+
+    assert_eq!(ids(&index, "\"#tag foo\"~2"), vec!["1"]);
+    assert_eq!(ids(&index, "\"#tag foo\""), vec!["1"]);
+    assert_eq!(ids(&index, "#tag"), vec!["1"]);
+    assert_eq!(ids(&index, "\\tag"), vec!["3"]);
+  }
+
+  #[test]
+  fn prefixes_only_expand_to_words_starting_with_them() {
+    let index = tags_index();
+    assert_eq!(ids(&index, "ta*"), vec!["2"]);
+    assert_eq!(ids(&index, "#ta*"), vec!["1"]);
+    assert_eq!(ids(&index, "\\ta*"), vec!["3"]);
+    assert_eq!(ids(&index, "ba*"), vec!["2", "3"]);
+    assert!(ids(&index, "tb*").is_empty());
+  }
+
+  #[test]
+  fn fuzzy_match_folds_sharp_s_to_two_letters() {
+    let fields: Vec<Field> = serde_json::from_value(json!(["name"])).unwrap();
+    let mut index = create(fields, Settings::default());
+    add_object(&mut index, json!({"_id": "1", "name": "Müller Straße"}), OnConflict::Reject).unwrap();
+
+    for query in ["strasse", "müller straße", "muller strasse"].iter() {
+      assert_eq!(ids(&index, query), vec!["1"]);
+    }
+
+    // Both letters of "ss" highlight the one "ß"
+    let hits = search(&index, String::from("strasse"), false, &|_| true, false).unwrap().hits;
+    assert_eq!(hits[0].matches, vec![7, 8, 9, 10, 11, 12]);
+  }
+
+  #[test]
+  fn words_the_fuzzy_match_cannot_take_are_highlighted() {
+    let fields: Vec<Field> = serde_json::from_value(json!(["name", "city"])).unwrap();
+    let mut index = create(fields, Settings::default());
+    add_object(&mut index, json!({"_id": "1", "name": "東京タワー 模型", "city": "Москва"}), OnConflict::Reject).unwrap();
+    add_object(&mut index, json!({"_id": "2", "name": "大阪城", "city": "Москва"}), OnConflict::Reject).unwrap();
+
+    let hits = search(&index, String::from("東京"), false, &|_| true, false).unwrap().hits;
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].matches, vec![0, 1]);
+    let highlights = highlight(&index, &index.items[&hits[0].iid], &hits[0], None, "<em>", "</em>", 100);
+    assert_eq!(highlights.len(), 1);
+    assert_eq!(highlights[0].field, "name");
+    assert_eq!(highlights[0].ranges, vec![(0, 2)]);
+
+    // Offsets count the chars of the fields before
+    let hits = search(&index, String::from("москва"), false, &|_| true, false).unwrap().hits;
+    assert_eq!(hits.len(), 2);
+    let hit = hits.iter().find(|x| index.items[&x.iid]["_id"] == "1").unwrap();
+    assert_eq!(hit.matches, (9..15).collect::<Vec<usize>>());
+  }
+
+  fn jackets_index() -> Index {
+    let fields: Vec<Field> = serde_json::from_value(json!(["name"])).unwrap();
+    let mut index = create(fields, Settings::default());
+    for i in 0..10 {
+      add_object(&mut index, json!({"_id": i.to_string(), "name": "Leather Jackets"}), OnConflict::Reject).unwrap();
+    }
+    add_object(&mut index, json!({"_id": "10", "name": "lather soap"}), OnConflict::Reject).unwrap();
+    return index;
+  }
+
+  #[test]
+  fn misspelled_words_get_corrected() {
+    let index = jackets_index();
+    assert_eq!(correct_word(&index, "lether").unwrap().0, "leather");
+    assert_eq!(correct_word(&index, "lather").unwrap().0, "leather");
+    assert!(correct_word(&index, "leather").is_none());
+    assert!(correct_word(&index, "soap").is_none());
+
+    // Corrections are spelled like the document has them, not stemmed
+    assert_eq!(suggest(&index, "lether jakcets").unwrap(), "leather jackets");
+    assert_eq!(suggest(&index, "Lether, soap!").unwrap(), "leather, soap!");
+    assert_eq!(suggest(&index, "nmae:lether").unwrap(), "nmae:leather");
+    assert!(suggest(&index, "lethe* soap").is_none());
+    assert!(suggest(&index, "leather jackets").is_none());
+  }
+
+  #[test]
+  fn explanations_add_up_to_the_token_score() {
+    let fields: Vec<Field> = serde_json::from_value(json!(["name"])).unwrap();
+    let mut index = create(fields, Settings::default());
+    let names = ["red leather jacket", "red cotton shirt", "blue jacket, red lining", "green scarf"];
+    for (i, name) in names.iter().enumerate() {
+      add_object(&mut index, json!({"_id": i.to_string(), "name": name}), OnConflict::Reject).unwrap();
+    }
+
+    for query in ["red jackett", "\"red leather\" jacket", "red OR scarf"].iter() {
+      let hits = search(&index, query.to_string(), true, &|_| true, false).unwrap().hits;
+      assert!(hits.len() > 0);
+      let best = hits.iter().map(|x| x.explanation.as_ref().unwrap().token_score).fold(0.0, f32::max);
+      for hit in hits.iter() {
+        let explanation = hit.explanation.as_ref().unwrap();
+        let sum: f32 = explanation.tokens.iter().map(|x| x.score).sum::<f32>() + explanation.phrase_bonus;
+        assert!((sum - explanation.token_score).abs() < 1e-3, "{}: {} != {}", query, sum, explanation.token_score);
+        assert!((explanation.relevance - explanation.token_score / best).abs() < 1e-3);
+        assert_eq!(explanation.cutoff, best / 2.0);
+      }
+    }
+
+    // The word with a typo counts less than the one as typed
+    let hits = search(&index, String::from("red jackett"), true, &|_| true, false).unwrap().hits;
+    let first = hits.iter().find(|x| index.items[&x.iid]["_id"] == "0").unwrap();
+    let tokens = &first.explanation.as_ref().unwrap().tokens;
+    let red = tokens.iter().find(|x| x.kind == "word" && x.token == "red").unwrap();
+    let jacket = tokens.iter().find(|x| x.kind == "word" && x.token == "jacket").unwrap();
+    assert_eq!((red.typos, jacket.typos), (0, 1));
+    assert!(jacket.weight < red.weight);
+    assert!(tokens.iter().any(|x| x.kind == "ngram"));
+
+    // Only phrases in the document get a bonus
+    let hits = search(&index, String::from("\"red leather\" jacket"), true, &|_| true, false).unwrap().hits;
+    for hit in hits.iter() {
+      let bonus = hit.explanation.as_ref().unwrap().phrase_bonus;
+      assert_eq!(bonus > 0.0, index.items[&hit.iid]["_id"] == "0");
+    }
+
+    let hits = search(&index, String::from("red"), false, &|_| true, false).unwrap().hits;
+    assert!(hits.iter().all(|x| x.explanation.is_none()));
+  }
+
+  #[test]
+  fn filters_apply_before_the_candidate_limit() {
+    let fields: Vec<Field> = serde_json::from_value(json!(["name"])).unwrap();
+    let mut settings = Settings::default();
+    settings.max_candidates = 3;
+    let mut index = create(fields, settings);
+    for i in 0..10 {
+      // The rarer kind comes last, with a longer name that scores lower
+      let name = if i < 8 { "red dress" } else { "red dress long" };
+      add_object(&mut index, json!({"_id": i.to_string(), "name": name, "kind": i / 8}), OnConflict::Reject).unwrap();
+    }
+
+    let all = search(&index, String::from("red dress"), false, &|_| true, false).unwrap();
+    assert_eq!(all.hits.len(), 3);
+    assert!(all.truncated);
+
+    let rare = |x: &Value| x["kind"] == json!(1);
+    let filtered = search(&index, String::from("red dress"), false, &rare, false).unwrap();
+    assert_eq!(filtered.hits.len(), 2);
+    assert!(!filtered.truncated);
+    assert_eq!(filtered.filtered_out, 8);
+
+    let uncapped = search(&index, String::from("red dress"), false, &|_| true, true).unwrap();
+    assert_eq!(uncapped.hits.len(), 10);
+    assert!(!uncapped.truncated);
+  }
+
+  #[test]
+  fn bm25_parameters_are_validated() {
+    let fields: Vec<Field> = serde_json::from_value(json!(["name"])).unwrap();
+    let valid = [(0.0, 0.0), (1.2, 0.75), (3.0, 1.0)];
+    for (k1, b) in valid.iter() {
+      let mut settings = Settings { k1: *k1, b: *b, ..Settings::default() };
+      assert!(validate_settings(&fields, &mut settings).is_ok());
+    }
+
+    let invalid = [(-0.1, 0.75), (1.2, -0.1), (1.2, 1.1), (f32::NAN, 0.75), (1.2, f32::NAN), (f32::INFINITY, 0.75)];
+    for (k1, b) in invalid.iter() {
+      let mut settings = Settings { k1: *k1, b: *b, ..Settings::default() };
+      assert!(validate_settings(&fields, &mut settings).is_err());
+    }
+
+    for boost in [0.5, 1.0, 3.0].iter() {
+      let fields: Vec<Field> = serde_json::from_value(json!([{"name": "name", "boost": boost}])).unwrap();
+      assert!(validate_settings(&fields, &mut Settings::default()).is_ok());
+    }
+    let mut boosted = fields.clone();
+    for boost in [0.0, -1.2, f32::NAN, f32::INFINITY].iter() {
+      boosted[0].boost = *boost;
+      assert!(validate_settings(&boosted, &mut Settings::default()).is_err());
+    }
+  }
+
+  #[test]
+  fn at_most_256_fields() {
+    let names = |count: usize| -> Vec<Field> {
+      serde_json::from_value(json!((0..count).map(|x| format!("f{}", x)).collect::<Vec<String>>())).unwrap()
+    };
+    assert!(validate_settings(&names(256), &mut Settings::default()).is_ok());
+    assert!(validate_settings(&names(257), &mut Settings::default()).is_err());
+  }
+
+  #[test]
+  fn phrase_distance_without_positions() {
+    let empty: &[u32] = &[];
+    assert_eq!(phrase_distance(&vec![&[0, 4][..], empty], &vec![0, 1], false), None);
+    assert_eq!(phrase_distance(&vec![&[0, 4][..], empty], &vec![0, 1], true), None);
+    assert_eq!(phrase_distance(&vec![&[0, 4][..], &[2][..]], &vec![0, 1], false), Some(1));
+    assert_eq!(phrase_distance(&vec![&[0, 4][..], &[5][..]], &vec![0, 1], true), Some(0));
+  }
 }
@@ -0,0 +1,149 @@
+use std::cmp;
+
+// Typos a word can have and still match, short words have too many
+// neighbours. Words with digits are numbers or codes and only match as
+// they are.
+pub fn max_typos(word: &str) -> usize {
+  if word.chars().any(|c| c.is_numeric()) {
+    return 0;
+  }
+  match word.chars().count() {
+    0..=3 => 0,
+    4..=7 => 1,
+    _ => 2,
+  }
+}
+
+// Accepts the strings at most `max` edits away from a word. An edit adds,
+// removes or replaces a char, or swaps two neighbouring ones. Strings are
+// fed to it a char at a time, so a walk over sorted terms can share the
+// states of a common prefix.
+pub struct Automaton {
+  chars: Vec<char>,
+  max: usize,
+}
+
+// Edits between the chars fed so far and every prefix of the word
+#[derive(Clone)]
+pub struct State {
+  row: Vec<usize>,
+  previous: Vec<usize>,
+  last: Option<char>,
+}
+
+impl Automaton {
+  pub fn new(word: &str, max: usize) -> Automaton {
+    Automaton { chars: word.chars().collect(), max }
+  }
+
+  pub fn start(&self) -> State {
+    State { row: (0..=self.chars.len()).collect(), previous: Vec::new(), last: None }
+  }
+
+  pub fn step(&self, state: &State, c: char) -> State {
+    let mut row = vec![state.row[0] + 1; self.chars.len() + 1];
+    for j in 1..=self.chars.len() {
+      let cost = if self.chars[j - 1] == c { 0 } else { 1 };
+      row[j] = cmp::min(cmp::min(state.row[j] + 1, row[j - 1] + 1), state.row[j - 1] + cost);
+
+      let swapped = j > 1 && state.last == Some(self.chars[j - 1]) && self.chars[j - 2] == c;
+      if swapped {
+        row[j] = cmp::min(row[j], state.previous[j - 2] + 1);
+      }
+    }
+    State { row, previous: state.row.clone(), last: Some(c) }
+  }
+
+  // Whether any string starting with what was fed can still match
+  pub fn can_match(&self, state: &State) -> bool {
+    state.row.iter().any(|x| *x <= self.max)
+  }
+
+  // Edits to the whole word, None when there are too many
+  pub fn distance(&self, state: &State) -> Option<usize> {
+    let distance = state.row[self.chars.len()];
+    if distance > self.max {
+      return None;
+    }
+    return Some(distance);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  // Edits from word to s, None when there are more than max
+  fn distance(word: &str, max: usize, s: &str) -> Option<usize> {
+    let automaton = Automaton::new(word, max);
+    let mut state = automaton.start();
+    for c in s.chars() {
+      state = automaton.step(&state, c);
+    }
+    automaton.distance(&state)
+  }
+
+  #[test]
+  fn edits() {
+    assert_eq!(distance("dress", 2, "dress"), Some(0));
+    assert_eq!(distance("dress", 2, "dres"), Some(1));
+    assert_eq!(distance("dress", 2, "dresss"), Some(1));
+    assert_eq!(distance("dress", 2, "drass"), Some(1));
+    assert_eq!(distance("dress", 2, "dr"), None);
+    assert_eq!(distance("dress", 1, "drs"), None);
+  }
+
+  #[test]
+  fn transpositions_are_one_edit() {
+    assert_eq!(distance("shirt", 1, "shrit"), Some(1));
+    assert_eq!(distance("shirt", 1, "hsirt"), Some(1));
+    assert_eq!(distance("shirt", 1, "shitr"), Some(1));
+    assert_eq!(distance("shirt", 2, "hsitr"), Some(2));
+    // Only neighbouring chars swap
+    assert_eq!(distance("ab", 2, "ba"), Some(1));
+    assert_eq!(distance("abc", 1, "cba"), None);
+  }
+
+  #[test]
+  fn typo_budget() {
+    assert_eq!(max_typos(""), 0);
+    assert_eq!(max_typos("red"), 0);
+    assert_eq!(max_typos("blue"), 1);
+    assert_eq!(max_typos("sweater"), 1);
+    assert_eq!(max_typos("trousers"), 2);
+    // Chars, not bytes
+    assert_eq!(max_typos("été"), 0);
+    assert_eq!(max_typos("ñandú"), 1);
+    // Numbers and codes only match as they are
+    assert_eq!(max_typos("12345678"), 0);
+    assert_eq!(max_typos("sku12345"), 0);
+  }
+
+  #[test]
+  fn empty_word() {
+    let automaton = Automaton::new("", 1);
+    let start = automaton.start();
+    assert_eq!(automaton.distance(&start), Some(0));
+    assert!(automaton.can_match(&start));
+
+    let state = automaton.step(&start, 'a');
+    assert_eq!(automaton.distance(&state), Some(1));
+    let state = automaton.step(&state, 'b');
+    assert_eq!(automaton.distance(&state), None);
+    assert!(!automaton.can_match(&state));
+  }
+
+  #[test]
+  fn prefixes_that_cannot_match() {
+    let automaton = Automaton::new("dress", 1);
+    let mut state = automaton.start();
+    for c in "dr".chars() {
+      state = automaton.step(&state, c);
+    }
+    assert!(automaton.can_match(&state));
+    for c in "xx".chars() {
+      state = automaton.step(&state, c);
+    }
+    assert!(!automaton.can_match(&state));
+  }
+}
@@ -0,0 +1,29 @@
+// Indexing, search and persistence without the HTTP server, what main.rs
+// serves and the benches measure
+#[macro_use]
+extern crate serde_derive;
+
+use serde_json::{Value};
+
+pub mod lp;
+pub mod index;
+pub mod postings;
+pub mod query;
+pub mod persist;
+pub mod wal;
+pub mod registry;
+pub mod aggs;
+pub mod synonyms;
+pub mod trie;
+pub mod levenshtein;
+
+pub fn dot_notation(obj: &Value, path: String) -> Value {
+  let keys: Vec<String> = path.split(".").map(String::from).collect();
+
+  let mut curr = obj;
+  for key in keys {
+    curr = &curr[key];
+  }
+
+  return curr.clone();
+}
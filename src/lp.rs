@@ -1,12 +1,181 @@
 
+use std::collections::BTreeMap;
 use rust_stemmers::{Algorithm, Stemmer};
 use ngrams::Ngram;
-use regex::Regex;
+use unicode_normalization::UnicodeNormalization;
+use unicode_normalization::char::is_combining_mark;
+use unicode_segmentation::UnicodeSegmentation;
+
+// Stemmer used for the words of an index or field, None keeps words as
+// they are
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Language {
+  None,
+  Arabic,
+  Danish,
+  Dutch,
+  English,
+  Finnish,
+  French,
+  German,
+  Greek,
+  Hungarian,
+  Italian,
+  Norwegian,
+  Portuguese,
+  Romanian,
+  Russian,
+  Spanish,
+  Swedish,
+  Tamil,
+  Turkish,
+}
+
+impl Default for Language {
+  fn default() -> Language {
+    Language::English
+  }
+}
+
+impl Language {
+  fn stemmer(self) -> Option<Stemmer> {
+    let algorithm = match self {
+      Language::None => return None,
+      Language::Arabic => Algorithm::Arabic,
+      Language::Danish => Algorithm::Danish,
+      Language::Dutch => Algorithm::Dutch,
+      Language::English => Algorithm::English,
+      Language::Finnish => Algorithm::Finnish,
+      Language::French => Algorithm::French,
+      Language::German => Algorithm::German,
+      Language::Greek => Algorithm::Greek,
+      Language::Hungarian => Algorithm::Hungarian,
+      Language::Italian => Algorithm::Italian,
+      Language::Norwegian => Algorithm::Norwegian,
+      Language::Portuguese => Algorithm::Portuguese,
+      Language::Romanian => Algorithm::Romanian,
+      Language::Russian => Algorithm::Russian,
+      Language::Spanish => Algorithm::Spanish,
+      Language::Swedish => Algorithm::Swedish,
+      Language::Tamil => Algorithm::Tamil,
+      Language::Turkish => Algorithm::Turkish,
+    };
+    Some(Stemmer::create(algorithm))
+  }
+}
+
+// Rewrites text before it's split into words
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CharFilter {
+  // Drops tags and decodes the common entities
+  HtmlStrip,
+  // Replaces every key with its value, the longest key first
+  Mapping(BTreeMap<String, String>),
+}
+
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Tokenizer {
+  // Unicode word boundaries (UAX #29), runs of CJK chars as single chars
+  Standard,
+  // Like standard, with CJK runs as overlapping pairs of chars
+  CjkBigram,
+  // Anything between whitespace, punctuation included
+  Whitespace,
+}
+
+impl Default for Tokenizer {
+  fn default() -> Tokenizer {
+    Tokenizer::Standard
+  }
+}
+
+// Either the name of a built-in list or the words themselves
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum StopWords {
+  Builtin(Language),
+  List(Vec<String>),
+}
+
+fn default_true() -> bool {
+  true
+}
+
+fn default_ngram() -> usize {
+  3
+}
+
+// How text is turned into words and n-grams, the same way for documents
+// and queries. Indexes define named analyzers in their settings that
+// fields refer to.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Analyzer {
+  #[serde(default)]
+  pub char_filters: Vec<CharFilter>,
+  #[serde(default)]
+  pub tokenizer: Tokenizer,
+  #[serde(default = "default_true")]
+  pub lowercase: bool,
+  #[serde(default = "default_true")]
+  pub fold_accents: bool,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub stop_words: Option<StopWords>,
+  #[serde(default)]
+  pub stemmer: Language,
+  // Size of the n-grams that find documents with typos
+  #[serde(default = "default_ngram")]
+  pub ngram: usize,
+}
+
+// Most chars per n-gram
+const MAX_NGRAM: usize = 5;
+
+// Strips accents and spells out letters that don't decompose, so "Müller",
+// "muller" and "MÜLLER" all end up as "muller"
+pub fn fold_accents(s: &str) -> String {
+  let mut folded = String::with_capacity(s.len());
+  for c in s.nfkd() {
+    match c {
+      'ß' => folded.push_str("ss"),
+      'æ' => folded.push_str("ae"),
+      'œ' => folded.push_str("oe"),
+      'ø' => folded.push('o'),
+      'ł' => folded.push('l'),
+      'đ' => folded.push('d'),
+      '’' => folded.push('\''),
+      c if is_combining_mark(c) => {}
+      c => folded.push(c),
+    }
+  }
+  return folded;
+}
+
+// fold_accents one char at a time, with the position in `s` of the char
+// every char of the result comes from, "ß" folds to "ss" at [0, 0]
+pub fn fold_chars(s: &str) -> (String, Vec<usize>) {
+  let mut folded = String::with_capacity(s.len());
+  let mut origins = Vec::with_capacity(s.len());
+  for (i, c) in s.chars().enumerate() {
+    if c.is_ascii() {
+      folded.push(c);
+      origins.push(i);
+      continue;
+    }
+    for f in fold_accents(&c.to_string()).chars() {
+      folded.push(f);
+      origins.push(i);
+    }
+  }
+  return (folded, origins);
+}
 
 pub fn get_first_chars(words: Vec<String>) -> Vec<String> {
   return words.iter().map(|word| {
     if word.len() > 0 {
-      let first_char = String::from(word.chars().nth(0).unwrap().to_string());
+      let first_char = fold_accents(&word.chars().nth(0).unwrap().to_string());
       return String::from(format!("${}", first_char));
     }
     return String::from("");
@@ -17,53 +186,485 @@ pub fn pad_string(s: String) -> String {
   String::from(format!("${}$", s))
 }
 
-pub fn clean_words(s: String) -> Vec<String> {
-  let en_stemmer = Stemmer::create(Algorithm::English);
-  let regex = Regex::new(r"[^a-zA-Z0-9]").unwrap();
-  let result = regex.replace_all(&s, " ").to_lowercase();
-  return get_words(result)
-    .iter()
-    .filter(|x| x.len() >= 2)
-    .map(|x| String::from(en_stemmer.stem(x)))
-    .collect();
+// Chinese, Japanese and Korean, written without spaces between words
+fn is_cjk(c: char) -> bool {
+  match c as u32 {
+    0x1100..=0x11FF | 0x2E80..=0x2FDF | 0x3040..=0x30FF | 0x3100..=0x31FF |
+    0x3400..=0x4DBF | 0x4E00..=0x9FFF | 0xA960..=0xA97F | 0xAC00..=0xD7AF |
+    0xF900..=0xFAFF | 0xFF66..=0xFF9F | 0x20000..=0x2FA1F => true,
+    _ => false,
+  }
 }
 
-pub fn get_words(s: String) -> Vec<String> {
-  return s.split(" ")
-    .map(|x| String::from(x))
-    .collect();
+// Pictographs and symbols people search for, like 🎉 or ☕
+fn is_emoji(c: char) -> bool {
+  match c as u32 {
+    0x2600..=0x27BF | 0x1F000..=0x1FAFF => true,
+    _ => false,
+  }
+}
+
+// Tokens that get indexed, a single letter or digit is too common to be
+// worth it
+fn is_word(word: &str, cjk: bool) -> bool {
+  cjk || word.chars().count() >= 2 || word.chars().any(is_emoji)
 }
 
-pub fn gramify(s: String) -> Vec<String> {
-  if s.len() == 1 {
-    return vec!(
-      String::from(format!("${}", s))
-    );
+// Words by Unicode word boundaries (UAX #29), whether they are CJK and the
+// byte offset they start at. UAX #29 splits CJK text into single chars,
+// chars that follow each other are joined back into one run here.
+fn segment(s: &str) -> Vec<(String, bool, usize)> {
+  let mut words: Vec<(String, bool, usize)> = Vec::new();
+  let mut cjk_end = None;
+
+  let segments = s
+    .split_word_bound_indices()
+    .filter(|(_, x)| x.chars().any(|c| c.is_alphanumeric() || is_emoji(c)));
+  for (start, word) in segments {
+    let mut offset = start;
+    let chars: Vec<char> = word.chars().collect();
+    for part in split_runs(&chars) {
+      let text: String = part.iter().collect();
+      let cjk = is_cjk(part[0]);
+      let end = offset + text.len();
+
+      if cjk && cjk_end == Some(offset) {
+        words.last_mut().unwrap().0.push_str(&text);
+      } else {
+        words.push((text, cjk, offset));
+      }
+      cjk_end = if cjk { Some(end) } else { None };
+      offset = end;
+    }
   }
-  if s.len() == 2 {
-    return vec!(
-      String::from(format!("${}", String::from(s.chars().nth(0).unwrap().to_string()))),
-      String::from(format!("{}$", String::from(s.chars().nth(1).unwrap().to_string())))
-    );
+  return words;
+}
+
+// Runs of chars between whitespace, with the byte offset they start at
+fn split_whitespace(s: &str) -> Vec<(String, bool, usize)> {
+  let mut words: Vec<(String, bool, usize)> = Vec::new();
+  let mut start = None;
+  for (i, c) in s.char_indices().chain(std::iter::once((s.len(), ' '))) {
+    match start {
+      Some(x) if c.is_whitespace() => {
+        words.push((s[x..i].to_string(), false, x));
+        start = None;
+      }
+      None if !c.is_whitespace() => start = Some(i),
+      _ => {}
+    }
   }
-  if s.len() > 2 {
-    let prepared_string: String = clean_words(s.clone()).join(" ");
+  return words;
+}
 
-    let mut tokens: Vec<String> = Vec::new();
+// Splits chars where they change between CJK and other scripts
+fn split_runs(chars: &[char]) -> Vec<&[char]> {
+  let mut runs = Vec::new();
+  let mut start = 0;
+  for i in 1..chars.len() {
+    if is_cjk(chars[i]) != is_cjk(chars[i - 1]) {
+      runs.push(&chars[start..i]);
+      start = i;
+    }
+  }
+  if chars.len() > 0 {
+    runs.push(&chars[start..]);
+  }
+  return runs;
+}
 
-    if prepared_string.len() > 0 {
-      let grams: Vec<_> = prepared_string.chars().ngrams(3).collect();
-      for gram in grams.into_iter() {
-        let s: Vec<String> = gram.into_iter().map(|x| x.to_string()).collect();
-        tokens.push(s.join(""));
+fn strip_html(s: &str) -> String {
+  let mut stripped = String::with_capacity(s.len());
+  let mut in_tag = false;
+  for c in s.chars() {
+    match c {
+      '<' => in_tag = true,
+      // Tags separate words
+      '>' if in_tag => {
+        in_tag = false;
+        stripped.push(' ');
       }
+      c if !in_tag => stripped.push(c),
+      _ => {}
     }
+  }
 
-    for c in get_first_chars(get_words(s)) {
-      tokens.push(c.to_lowercase());
+  let entities = [("&nbsp;", " "), ("&lt;", "<"), ("&gt;", ">"), ("&quot;", "\""), ("&#39;", "'"), ("&amp;", "&")];
+  for (entity, replacement) in entities.iter() {
+    stripped = stripped.replace(entity, replacement);
+  }
+  return stripped;
+}
+
+fn map_chars(s: &str, mapping: &BTreeMap<String, String>) -> String {
+  let mut keys: Vec<&String> = mapping.keys().collect();
+  keys.sort_by(|a, b| b.len().cmp(&a.len()));
+
+  let mut mapped = String::with_capacity(s.len());
+  let mut rest = s;
+  while let Some(c) = rest.chars().next() {
+    match keys.iter().find(|x| rest.starts_with(x.as_str())) {
+      Some(key) => {
+        mapped.push_str(&mapping[*key]);
+        rest = &rest[key.len()..];
+      }
+      None => {
+        mapped.push(c);
+        rest = &rest[c.len_utf8()..];
+      }
     }
+  }
+  return mapped;
+}
 
-    return tokens;
+impl Analyzer {
+  // What fields that don't name an analyzer get
+  pub fn standard(language: Language, cjk_bigrams: bool) -> Analyzer {
+    Analyzer {
+      char_filters: Vec::new(),
+      tokenizer: if cjk_bigrams { Tokenizer::CjkBigram } else { Tokenizer::Standard },
+      lowercase: true,
+      fold_accents: true,
+      stop_words: None,
+      stemmer: language,
+      ngram: default_ngram(),
+    }
+  }
+
+  // Checks the settings and sorts custom stop words for lookups
+  pub fn validate(&mut self) -> Result<(), String> {
+    if self.ngram == 0 || self.ngram > MAX_NGRAM {
+      return Err(format!("ngram has to be between 1 and {}", MAX_NGRAM));
+    }
+
+    for filter in self.char_filters.iter() {
+      if let CharFilter::Mapping(mapping) = filter {
+        if mapping.keys().any(|x| x.len() == 0) {
+          return Err(String::from("Mapping keys can't be empty"));
+        }
+      }
+    }
+
+    let lowercase = self.lowercase;
+    match &mut self.stop_words {
+      Some(StopWords::Builtin(language)) => {
+        if builtin_stop_words(*language).is_none() {
+          return Err(format!("No built-in stop words for {:?}", language));
+        }
+      }
+      Some(StopWords::List(words)) => {
+        if lowercase {
+          for word in words.iter_mut() {
+            *word = word.to_lowercase();
+          }
+        }
+        words.sort();
+        words.dedup();
+      }
+      None => {}
+    }
+    return Ok(());
+  }
+
+  fn filter_chars(&self, s: &str) -> String {
+    let mut filtered = s.to_string();
+    for filter in self.char_filters.iter() {
+      filtered = match filter {
+        CharFilter::HtmlStrip => strip_html(&filtered),
+        CharFilter::Mapping(mapping) => map_chars(&filtered, mapping),
+      };
+    }
+    return filtered;
+  }
+
+  // Char filters, tokenizer and lowercasing
+  fn tokens(&self, s: &str) -> Vec<(String, bool)> {
+    return self.spans(&self.filter_chars(s))
+      .into_iter()
+      .map(|(x, cjk, _, _)| (x, cjk))
+      .collect();
+  }
+
+  // Tokenizer and lowercasing on text that went through the char filters,
+  // with the range of chars of each token
+  fn spans(&self, filtered: &str) -> Vec<(String, bool, usize, usize)> {
+    let tokens = match self.tokenizer {
+      Tokenizer::Whitespace => split_whitespace(filtered),
+      _ => segment(filtered),
+    };
+
+    let mut spans = Vec::new();
+    let mut byte = 0;
+    let mut chars = 0;
+    for (token, cjk, start) in tokens {
+      chars += filtered[byte..start].chars().count();
+      byte = start;
+      let length = token.chars().count();
+      let token = if self.lowercase { token.to_lowercase() } else { token };
+      spans.push((token, cjk, chars, chars + length));
+    }
+    return spans;
+  }
+
+  fn is_stop_word(&self, word: &str) -> bool {
+    match &self.stop_words {
+      Some(StopWords::Builtin(language)) => builtin_stop_words(*language).map_or(false, |x| x.contains(&word)),
+      Some(StopWords::List(words)) => words.binary_search_by(|x| x.as_str().cmp(word)).is_ok(),
+      None => false,
+    }
+  }
+
+  // Stems a word, then folds its accents, stemmers expect the accents to
+  // still be there. English words have none, so loanwords like "café" get
+  // folded first and stemmed like their plain spelling. CJK runs are kept
+  // whole.
+  fn stem(&self, stemmer: &Option<Stemmer>, word: String, cjk: bool) -> String {
+    if cjk {
+      // Full width forms, without folding away kana voicing marks
+      return word.nfkc().collect();
+    }
+
+    let fold_first = self.fold_accents && self.stemmer == Language::English;
+    let word = match stemmer {
+      Some(stemmer) if fold_first => stemmer.stem(&fold_accents(&word)).to_string(),
+      Some(stemmer) => stemmer.stem(&word).to_string(),
+      None => word,
+    };
+    if self.fold_accents && !fold_first {
+      return fold_accents(&word);
+    }
+    return word;
+  }
+
+  // Words with at least 2 chars, stemmed, and the chars they span in the
+  // filtered text. Stop words are None, they still take up a position.
+  fn analyze(&self, filtered: &str) -> Vec<(Option<(String, bool)>, usize, usize)> {
+    let stemmer = self.stemmer.stemmer();
+
+    return self.spans(filtered)
+      .into_iter()
+      .filter(|(word, cjk, _, _)| is_word(word, *cjk))
+      .map(|(word, cjk, start, end)| {
+        if self.is_stop_word(&word) {
+          return (None, start, end);
+        }
+        return (Some((self.stem(&stemmer, word, cjk), cjk)), start, end);
+      })
+      .collect();
+  }
+
+  // Every token stemmed, short words and stop words included, what
+  // synonyms are matched on
+  pub fn terms(&self, s: &str) -> Vec<String> {
+    let stemmer = self.stemmer.stemmer();
+    return self.tokens(s)
+      .into_iter()
+      .map(|(word, cjk)| self.stem(&stemmer, word, cjk))
+      .collect();
+  }
+
+  // Whether s has words too short to be indexed that aren't stop words
+  pub fn drops_words(&self, s: &str) -> bool {
+    return self.tokens(s)
+      .iter()
+      .any(|(word, cjk)| !is_word(word, *cjk) && !self.is_stop_word(word));
+  }
+
+  // The words of s as they are written, lowercased but neither stemmed nor
+  // folded, without stop words
+  pub fn surface_words(&self, s: &str) -> Vec<String> {
+    return self.tokens(s)
+      .into_iter()
+      .filter(|(word, cjk)| is_word(word, *cjk) && !self.is_stop_word(word))
+      .map(|(word, _)| word)
+      .collect();
+  }
+
+  // The first word of s that is indexed as term, lowercased but not
+  // stemmed, how a suggestion for term gets written
+  pub fn spelling(&self, s: &str, term: &str) -> Option<String> {
+    let stemmer = self.stemmer.stemmer();
+    return self.tokens(s)
+      .into_iter()
+      .find(|(word, cjk)| !*cjk && self.stem(&stemmer, word.clone(), false) == term)
+      .map(|(word, _)| word);
+  }
+
+  // The words of s as they are indexed and searched for, with their
+  // positions. CJK runs become single chars, or overlapping pairs with the
+  // cjk_bigram tokenizer, a run of one char stays as it is.
+  pub fn words(&self, s: &str) -> Vec<(String, u32)> {
+    return self.located_words(&self.filter_chars(s))
+      .into_iter()
+      .map(|(word, position, _, _)| (word, position))
+      .collect();
+  }
+
+  // The words of s like `words` gives them, with the range of chars each
+  // comes from. None when the char filters change s, the ranges would be
+  // in the filtered text.
+  pub fn word_spans(&self, s: &str) -> Option<Vec<(String, usize, usize)>> {
+    if self.filter_chars(s) != s {
+      return None;
+    }
+    let spans = self.located_words(s)
+      .into_iter()
+      .map(|(word, _, start, end)| (word, start, end))
+      .collect();
+    return Some(spans);
+  }
+
+  // Words of the filtered text with their positions and chars. The chars
+  // of a CJK run that normalizing changed the length of all go to each of
+  // its words.
+  fn located_words(&self, filtered: &str) -> Vec<(String, u32, usize, usize)> {
+    let mut words = Vec::new();
+    let mut position = 0;
+    for (analyzed, start, end) in self.analyze(filtered) {
+      let (word, cjk) = match analyzed {
+        Some(x) => x,
+        None => {
+          position += 1;
+          continue;
+        }
+      };
+
+      let chars: Vec<char> = word.chars().collect();
+      let span = |i: usize, n: usize| {
+        if chars.len() == end - start {
+          return (start + i, start + i + n);
+        }
+        return (start, end);
+      };
+      if !cjk {
+        words.push((word, position, start, end));
+        position += 1;
+      } else if self.tokenizer == Tokenizer::CjkBigram && chars.len() > 1 {
+        for (i, pair) in chars.windows(2).enumerate() {
+          let (first, last) = span(i, 2);
+          words.push((pair.iter().collect(), position, first, last));
+          position += 1;
+        }
+      } else if self.tokenizer == Tokenizer::CjkBigram {
+        words.push((word, position, start, end));
+        position += 1;
+      } else {
+        for (i, c) in chars.iter().enumerate() {
+          let (first, last) = span(i, 1);
+          words.push((c.to_string(), position, first, last));
+          position += 1;
+        }
+      }
+    }
+    return words;
+  }
+
+  // A prefix as it would start an indexed word, it isn't stemmed
+  pub fn normalize(&self, s: &str) -> String {
+    let mut normalized = self.filter_chars(s);
+    if self.lowercase {
+      normalized = normalized.to_lowercase();
+    }
+    if self.fold_accents {
+      normalized = fold_accents(&normalized);
+    }
+    return normalized;
+  }
+
+  // N-grams of chars across the analyzed words, plus the first char of
+  // every word. Text of 1 or 2 chars gets marked grams of its chars.
+  pub fn grams(&self, s: &str) -> Vec<String> {
+    let length = s.chars().count();
+    if length == 1 {
+      return vec!(
+        String::from(format!("${}", s))
+      );
+    }
+    if length == 2 {
+      return vec!(
+        String::from(format!("${}", String::from(s.chars().nth(0).unwrap().to_string()))),
+        String::from(format!("{}$", String::from(s.chars().nth(1).unwrap().to_string())))
+      );
+    }
+    if length > 2 {
+      let words: Vec<String> = self.analyze(&self.filter_chars(s)).into_iter().filter_map(|x| x.0.map(|x| x.0)).collect();
+      let prepared_string = words.join(" ");
+
+      let mut tokens: Vec<String> = Vec::new();
+
+      if prepared_string.len() > 0 {
+        let grams: Vec<_> = prepared_string.chars().ngrams(self.ngram).collect();
+        for gram in grams.into_iter() {
+          let s: Vec<String> = gram.into_iter().map(|x| x.to_string()).collect();
+          tokens.push(s.join(""));
+        }
+      }
+
+      let tokens_of_s: Vec<String> = self.tokens(s).into_iter().map(|x| x.0).collect();
+      for c in get_first_chars(tokens_of_s) {
+        tokens.push(c);
+      }
+
+      return tokens;
+    }
+    return vec!();
+  }
+}
+
+fn builtin_stop_words(language: Language) -> Option<&'static [&'static str]> {
+  match language {
+    Language::English => Some(ENGLISH_STOP_WORDS),
+    Language::German => Some(GERMAN_STOP_WORDS),
+    Language::French => Some(FRENCH_STOP_WORDS),
+    Language::Spanish => Some(SPANISH_STOP_WORDS),
+    Language::Italian => Some(ITALIAN_STOP_WORDS),
+    Language::Dutch => Some(DUTCH_STOP_WORDS),
+    Language::Portuguese => Some(PORTUGUESE_STOP_WORDS),
+    _ => None,
   }
-  return vec!();
 }
+
+const ENGLISH_STOP_WORDS: &[&str] = &[
+  "a", "an", "and", "are", "as", "at", "be", "but", "by", "for", "if", "in", "into", "is", "it",
+  "no", "not", "of", "on", "or", "such", "that", "the", "their", "then", "there", "these",
+  "they", "this", "to", "was", "will", "with",
+];
+
+const GERMAN_STOP_WORDS: &[&str] = &[
+  "aber", "als", "am", "an", "auch", "auf", "aus", "bei", "bin", "bis", "da", "dann", "das",
+  "dass", "dem", "den", "der", "des", "die", "doch", "du", "durch", "ein", "eine", "einem",
+  "einen", "einer", "eines", "er", "es", "für", "hat", "ich", "ihr", "im", "in", "ist", "mit",
+  "nach", "nicht", "noch", "nur", "oder", "sie", "sind", "so", "um", "und", "uns", "von",
+  "vor", "war", "was", "wie", "wir", "zu", "zum", "zur",
+];
+
+const FRENCH_STOP_WORDS: &[&str] = &[
+  "au", "aux", "avec", "ce", "ces", "dans", "de", "des", "du", "elle", "en", "est", "et", "eux",
+  "il", "je", "la", "le", "les", "leur", "lui", "ma", "mais", "me", "mes", "moi", "mon", "ne",
+  "nos", "notre", "nous", "on", "ou", "par", "pas", "pour", "qu", "que", "qui", "sa", "se",
+  "ses", "son", "sur", "ta", "te", "tes", "toi", "ton", "tu", "un", "une", "vos", "votre", "vous",
+];
+
+const SPANISH_STOP_WORDS: &[&str] = &[
+  "al", "como", "con", "de", "del", "el", "en", "es", "esta", "este", "la", "las", "le", "les",
+  "lo", "los", "más", "mi", "no", "nos", "o", "para", "pero", "por", "que", "se", "si", "sin",
+  "su", "sus", "te", "tu", "un", "una", "uno", "y", "ya",
+];
+
+const ITALIAN_STOP_WORDS: &[&str] = &[
+  "a", "al", "alla", "anche", "che", "chi", "ci", "come", "con", "da", "del", "della", "di",
+  "e", "è", "gli", "ha", "i", "il", "in", "la", "le", "lo", "ma", "mi", "ne", "nel", "non",
+  "o", "per", "più", "se", "si", "su", "sua", "suo", "ti", "tra", "un", "una", "uno",
+];
+
+const DUTCH_STOP_WORDS: &[&str] = &[
+  "aan", "al", "als", "bij", "dan", "dat", "de", "die", "dit", "door", "een", "en", "er",
+  "het", "hij", "ik", "in", "is", "je", "maar", "met", "naar", "niet", "of", "om", "ook",
+  "op", "te", "tot", "uit", "van", "voor", "was", "wat", "we", "ze", "zijn",
+];
+
+const PORTUGUESE_STOP_WORDS: &[&str] = &[
+  "a", "ao", "aos", "as", "com", "como", "da", "das", "de", "do", "dos", "e", "é", "ela",
+  "ele", "em", "eu", "mais", "mas", "na", "nas", "no", "nos", "não", "o", "os", "ou", "para",
+  "pela", "pelo", "por", "que", "se", "sem", "seu", "sua", "um", "uma",
+];
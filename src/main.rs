@@ -9,6 +9,7 @@ extern crate serde_derive;
 extern crate md5;
 extern crate rand;
 extern crate sublime_fuzzy;
+extern crate ctrlc;
 
 use lazy_static::lazy_static;
 use rocket::config::{Config, Environment, Limits};
@@ -16,6 +17,8 @@ use rocket_contrib::json::{Json, JsonValue};
 use serde_json::{Value};
 use std::collections::HashMap;
 use std::sync::Mutex;
+use std::path::PathBuf;
+use std::thread;
 use rocket::http::{Status, ContentType};
 use rocket::response;
 use rocket::response::{Responder, Response};
@@ -24,13 +27,70 @@ use std::env;
 use rand::seq::SliceRandom;
 use rand::prelude::*;
 use std::cmp;
-use std::time::{SystemTime, Instant};
+use std::time::{SystemTime, Instant, Duration};
 
-mod lp;
-mod index;
+use gianna::{aggs, dot_notation, index, persist, registry, synonyms, wal};
 
 lazy_static! {
-  static ref INDEXES: Mutex<HashMap<String, index::Index>> = Mutex::new(HashMap::new());
+  static ref INDEXES: registry::Registry = registry::Registry::new();
+  static ref DATA_DIR: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+fn data_dir() -> Option<PathBuf> {
+  return DATA_DIR.lock().unwrap().clone();
+}
+
+fn wal_error(err: std::io::Error) -> ApiResponse {
+  println!("Failed to write operation log: {}", err);
+  return ApiResponse {
+    json: json!({
+      "status": 500,
+      "message": "Failed to write operation log",
+      "error": true
+    }),
+    status: Status::InternalServerError
+  }
+}
+
+fn query_error(message: String) -> ApiResponse {
+  return ApiResponse {
+    json: json!({
+      "status": 400,
+      "message": format!("Invalid query: {}", message),
+      "error": true
+    }),
+    status: Status::BadRequest
+  }
+}
+
+fn settings_error(message: String) -> ApiResponse {
+  return ApiResponse {
+    json: json!({
+      "status": 400,
+      "message": format!("Invalid settings: {}", message),
+      "error": true
+    }),
+    status: Status::BadRequest
+  }
+}
+
+fn bulk_rejected(results: Vec<wal::ItemResult>) -> ApiResponse {
+  return ApiResponse {
+    json: json!({
+      "status": 400,
+      "message": "Some items are invalid, no items were applied",
+      "error": true,
+      "errors": true,
+      "results": results
+    }),
+    status: Status::BadRequest
+  }
+}
+
+fn snapshot_indexes() {
+  if let Some(dir) = data_dir() {
+    INDEXES.snapshot(&dir);
+  }
 }
 
 fn calc_pages(max: u32, size: u32) -> u32 {
@@ -42,10 +102,6 @@ fn calc_pages(max: u32, size: u32) -> u32 {
   );
 }
 
-fn parse_json(datastr: String) -> Value {
-  return serde_json::from_str(&datastr).unwrap();
-}
-
 #[derive(Debug)]
 struct ApiResponse {
   json: JsonValue,
@@ -64,6 +120,11 @@ impl<'r> Responder<'r> for ApiResponse {
 #[derive(Clone, Serialize, Deserialize)]
 struct BulkImport {
   items: Vec<Value>,
+  // Only used when adding items: "reject" (default) or "upsert" items
+  // whose _id already exists
+  on_conflict: Option<index::OnConflict>,
+  // Apply either all items or, if any of them is invalid, none
+  atomic: Option<bool>,
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -88,17 +149,76 @@ struct SearchOptions {
   sort_by: Option<String>,
   sort_asc: Option<bool>,
   sort_type: Option<String>,
+
+  // "ids" (default) or "documents"
+  r#return: Option<String>,
+  // Dot notation paths to project the returned documents to
+  fields: Option<Vec<String>>,
+
+  highlight: Option<HighlightOptions>,
+
+  // Return how every item on the page was scored, filtered and ranked
+  explain: Option<bool>,
+
+  // Computed over all filtered items, before pagination
+  aggregations: Option<HashMap<String, aggs::Aggregation>>,
+
+  did_you_mean: Option<DidYouMeanOptions>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct DidYouMeanOptions {
+  // Suggest a corrected query when fewer items are found, defaults to 1
+  min_results: Option<usize>,
+  // Search the corrected query instead when it finds more items
+  auto_retry: Option<bool>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct HighlightOptions {
+  pre_tag: Option<String>,
+  post_tag: Option<String>,
+  // Only highlight these fields, defaults to all indexed fields
+  fields: Option<Vec<String>>,
+  // Fields longer than this are cut down around the first match
+  snippet_length: Option<usize>,
 }
 
-fn dot_notation(obj: &Value, path: String) -> Value {
-  let keys: Vec<String> = path.split(".").map(String::from).collect();
+// Copies the given dot notation paths of obj into a new object with the
+// same nesting, always keeping _id
+fn project(obj: &Value, paths: &Vec<String>) -> Value {
+  let mut result = Value::Object(serde_json::Map::new());
+  result["_id"] = obj["_id"].clone();
 
-  let mut curr = obj;
-  for key in keys {
-    curr = &curr[key];
+  for path in paths {
+    let value = dot_notation(obj, path.clone());
+    if value.is_null() {
+      continue;
+    }
+
+    let keys: Vec<&str> = path.split(".").collect();
+    let mut curr = &mut result;
+    for key in &keys[..keys.len() - 1] {
+      if !curr[*key].is_object() {
+        curr[*key] = Value::Object(serde_json::Map::new());
+      }
+      curr = &mut curr[*key];
+    }
+    curr[keys[keys.len() - 1]] = value;
   }
 
-  return curr.clone();
+  return result;
+}
+
+// The filter tree with whether every node of it matched obj
+fn explain_tree_node(tree: &FilterTree, obj: &Value) -> Value {
+  let mut explained = serde_json::to_value(tree).unwrap();
+  explained["matches"] = Value::Bool(check_tree_node(tree, obj));
+  if tree.children.is_some() {
+    let children = tree.children.as_ref().unwrap();
+    explained["children"] = Value::Array(children.iter().map(|x| explain_tree_node(x, obj)).collect());
+  }
+  return explained;
 }
 
 fn check_tree_node(tree: &FilterTree, obj: &Value) -> bool {
@@ -212,40 +332,54 @@ fn check_tree_node(tree: &FilterTree, obj: &Value) -> bool {
   return false;
 }
 
-fn get_items(index: &index::Index, query: Option<String>) -> Vec<Value> {
-  let items: Vec<Value>;
+// A document on its way through filtering, sorting and pagination, along
+// with the search hit that found it when there was a query
+struct SearchItem<'a> {
+  doc: &'a Value,
+  hit: Option<index::Hit>,
+}
+
+// The items that match the query and pass the filter, whether more
+// matched than were ranked (see index::SearchResult) and how many the
+// filter left out. The filter applies before ranking, so it can't empty a
+// page of the best candidates; `uncapped` ranks every match, for counts.
+// Fails on syntax errors in the query.
+fn get_items<'a>(index: &'a index::Index, query: Option<String>, explain: bool, filter: Option<&FilterTree>, uncapped: bool) -> Result<(Vec<SearchItem<'a>>, bool, usize), String> {
+  let keep = |obj: &Value| filter.map_or(true, |x| check_tree_node(x, obj));
 
   if query.clone().is_some() {
     println!("Searching '{}'", query.clone().unwrap());
-    let new_items = index::search(&index, String::from(query.clone().unwrap()));
-    let mut vec: Vec<Value> = vec![];
-    for item in new_items {
-      vec.push(parse_json(item));
+    let result = index::search(&index, String::from(query.clone().unwrap()), explain, &keep, uncapped)?;
+    let mut vec: Vec<SearchItem> = vec![];
+    for hit in result.hits {
+      let item = index.items.get(&hit.iid).unwrap();
+      vec.push(SearchItem { doc: item, hit: Some(hit) });
     }
-    items = vec;
+    return Ok((vec, result.truncated, result.filtered_out));
   }
-  else {
-    let mut vec: Vec<Value> = vec![];
-    for id in index.items.values() {
-      vec.push(parse_json(id.to_string()));
+
+  let mut vec: Vec<SearchItem> = vec![];
+  let mut filtered_out = 0;
+  for item in index.items.values() {
+    if !keep(item) {
+      filtered_out += 1;
+      continue;
     }
-    items = vec;
+    vec.push(SearchItem { doc: item, hit: None });
   }
-
-  return items;
+  return Ok((vec, false, filtered_out));
 }
 
 #[get("/<index_name>/times")]
 fn get_times(index_name: String) -> ApiResponse {
-  let indexes = INDEXES.lock().unwrap();
-
-  if indexes.contains_key(&index_name) {
-    let index = indexes.get(&index_name).unwrap();
+  if let Some(shared) = INDEXES.get(&index_name) {
+    let index = shared.read().unwrap();
+    let query_times = index.query_times.lock().unwrap();
   
     return ApiResponse {
       json: json!({
         "status": 200,
-        "query_times": index.query_times
+        "query_times": *query_times
       }),
       status: Status::Ok
     }
@@ -265,21 +399,72 @@ fn get_times(index_name: String) -> ApiResponse {
 fn search_items(index_name: String, input: Json<SearchOptions>, q: Option<String>, skip: Option<u32>, take: Option<u32>) -> ApiResponse {
   let now = Instant::now();
 
-  let mut indexes = INDEXES.lock().unwrap();
-
-  if indexes.contains_key(&index_name) {
-    let index = indexes.get_mut(&index_name).unwrap();
+  if let Some(shared) = INDEXES.get(&index_name) {
+    let index = shared.read().unwrap();
     let data = input.into_inner();
 
-    // Get items
-    let mut items = get_items(&index, q.clone());
+    let projection = data.fields.clone();
+    let return_documents = data.r#return
+      .as_ref()
+      .map_or(false, |x| x.cmp(&String::from("documents")) == std::cmp::Ordering::Equal);
+
+    let explain = data.explain.unwrap_or(false);
 
-    // Filter items
-    if data.filter.is_some() {
-      let filter_tree = data.filter.unwrap();
-      items.retain(|x| check_tree_node(&filter_tree, &x));
+    // Get and filter items
+    let found = get_items(&index, q.clone(), explain, data.filter.as_ref(), false);
+    if let Err(message) = found {
+      return query_error(message);
+    }
+    let (mut items, mut truncated, mut filtered_out) = found.unwrap();
+
+    // Suggest a spelling correction when too little was found
+    let mut suggestion = None;
+    let mut retried = false;
+    if data.did_you_mean.is_some() && q.is_some() {
+      let options = data.did_you_mean.as_ref().unwrap();
+      if items.len() < options.min_results.unwrap_or(1) {
+        suggestion = index::suggest(&index, q.as_ref().unwrap());
+      }
+
+      if suggestion.is_some() && options.auto_retry.unwrap_or(false) {
+        println!("Retrying with '{}'", suggestion.as_ref().unwrap());
+        if let Ok((corrected, corrected_truncated, corrected_filtered_out)) = get_items(&index, suggestion.clone(), explain, data.filter.as_ref(), false) {
+          if corrected.len() > items.len() {
+            items = corrected;
+            truncated = corrected_truncated;
+            filtered_out = corrected_filtered_out;
+            retried = true;
+          }
+        }
+      }
     }
     let num_items = items.len();
+
+    // Aggregate items
+    let mut aggregations = Value::Null;
+    if data.aggregations.is_some() {
+      let definitions = data.aggregations.as_ref().unwrap();
+      if let Err(message) = aggs::validate(definitions) {
+        return ApiResponse {
+          json: json!({
+            "status": 400,
+            "message": message,
+            "error": true
+          }),
+          status: Status::BadRequest
+        }
+      }
+
+      // Over every match, not just the ones that were ranked
+      let mut docs: Vec<&Value> = items.iter().map(|x| x.doc).collect();
+      if truncated {
+        let query = if retried { suggestion.clone() } else { q.clone() };
+        if let Ok((all, _, _)) = get_items(&index, query, false, data.filter.as_ref(), true) {
+          docs = all.iter().map(|x| x.doc).collect();
+        }
+      }
+      aggregations = aggs::compute(definitions, &docs);
+    }
     
     // Sort items
     if data.sort_by.is_some() {
@@ -302,8 +487,8 @@ fn search_items(index_name: String, input: Json<SearchOptions>, q: Option<String
         
         items.sort_by(|a, b| {
           if sort_type.cmp(&String::from("number")) == std::cmp::Ordering::Equal {
-            let a_maybe = dot_notation(a, sort_prop.clone());
-            let b_maybe = dot_notation(b, sort_prop.clone());
+            let a_maybe = dot_notation(a.doc, sort_prop.clone());
+            let b_maybe = dot_notation(b.doc, sort_prop.clone());
             let a_val = a_maybe.as_f64().unwrap_or(0.0);
             let b_val = b_maybe.as_f64().unwrap_or(0.0);
             if sort_asc == true {
@@ -312,8 +497,8 @@ fn search_items(index_name: String, input: Json<SearchOptions>, q: Option<String
             return a_val.partial_cmp(&b_val).unwrap()
           }
           else if sort_type.cmp(&String::from("string")) == std::cmp::Ordering::Equal {
-            let a_maybe = dot_notation(a, sort_prop.clone());
-            let b_maybe = dot_notation(b, sort_prop.clone());
+            let a_maybe = dot_notation(a.doc, sort_prop.clone());
+            let b_maybe = dot_notation(b.doc, sort_prop.clone());
             let a_val = a_maybe.as_str().unwrap_or("").to_lowercase();
             let b_val = b_maybe.as_str().unwrap_or("").to_lowercase();
             if sort_asc == true {
@@ -337,12 +522,64 @@ fn search_items(index_name: String, input: Json<SearchOptions>, q: Option<String
       .take(_take)
       .collect();
 
-    let mut ids: Vec<_> = page.iter().map(|x| {
-      let obj = parse_json(x.to_string());
-      let raw = obj["_id"].as_str().unwrap();
-      return String::from(raw);
+    let mut page_items: Vec<&SearchItem> = page.into_iter().map(|x| &*x).collect();
+    page_items.dedup_by(|a, b| a.doc["_id"] == b.doc["_id"]);
+
+    let results: Vec<Value> = page_items.iter().map(|x| {
+      if projection.is_some() {
+        return project(x.doc, projection.as_ref().unwrap());
+      }
+      if return_documents {
+        return x.doc.clone();
+      }
+      let raw = x.doc["_id"].as_str().unwrap();
+      return Value::String(String::from(raw));
     }).collect();
-    ids.dedup_by(|a, b| a.cmp(&b) == std::cmp::Ordering::Equal);
+
+    // Highlights by _id, only for hits that came from a query
+    let mut highlights = serde_json::Map::new();
+    if data.highlight.is_some() {
+      let options = data.highlight.as_ref().unwrap();
+      let pre_tag = options.pre_tag.clone().unwrap_or(String::from("<em>"));
+      let post_tag = options.post_tag.clone().unwrap_or(String::from("</em>"));
+      let max_length = options.snippet_length.unwrap_or(200);
+
+      for item in page_items.iter() {
+        if let Some(hit) = item.hit.as_ref() {
+          let id = item.doc["_id"].as_str().unwrap();
+          let item_highlights = index::highlight(&index, item.doc, hit, options.fields.as_ref(), &pre_tag, &post_tag, max_length);
+          highlights.insert(String::from(id), serde_json::to_value(item_highlights).unwrap());
+        }
+      }
+    }
+
+    // How every item on the page got there, by _id
+    let mut explanations = Value::Null;
+    if explain {
+      let mut hits = serde_json::Map::new();
+      for (i, item) in page_items.iter().enumerate() {
+        let id = item.doc["_id"].as_str().unwrap();
+        let mut explanation = json!({
+          "rank": _skip + i + 1,
+          "score": item.hit.as_ref().map(|x| x.score),
+        });
+        if let Some(hit_explanation) = item.hit.as_ref().and_then(|x| x.explanation.as_ref()) {
+          let details = serde_json::to_value(hit_explanation).unwrap();
+          for (key, value) in details.as_object().unwrap() {
+            explanation[key] = value.clone();
+          }
+        }
+        if data.filter.is_some() {
+          explanation["filter"] = explain_tree_node(data.filter.as_ref().unwrap(), item.doc);
+        }
+        hits.insert(String::from(id), explanation.into());
+      }
+
+      explanations = json!({
+        "filtered_out": filtered_out,
+        "hits": hits,
+      }).into();
+    }
 
     let num_pages = calc_pages(
       num_items as u32,
@@ -351,11 +588,12 @@ fn search_items(index_name: String, input: Json<SearchOptions>, q: Option<String
 
     let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis() as u64;
     let query_time = now.elapsed().as_nanos() as u64;
-    index.query_times.push_back(
+    let mut query_times = index.query_times.lock().unwrap();
+    query_times.push_back(
       (timestamp, query_time)
     );
-    if index.query_times.len() > 2500 {
-      index.query_times.pop_front().unwrap();
+    if query_times.len() > 2500 {
+      query_times.pop_front().unwrap();
     }
 
     return ApiResponse {
@@ -363,10 +601,169 @@ fn search_items(index_name: String, input: Json<SearchOptions>, q: Option<String
         "status": 200,
         "message": "Search successful",
         "query": q,
-        "items": ids,
+        "items": results,
         "max_items": num_items,
-        "num_items": ids.len(),
+        "num_items": results.len(),
         "num_pages": num_pages,
+        "highlights": highlights,
+        "aggregations": aggregations,
+        "suggestion": suggestion,
+        "retried": retried,
+        "truncated": truncated,
+        "explain": explanations,
+      }),
+      status: Status::Ok
+    }
+  }
+  else {
+    return ApiResponse {
+      json: json!({
+        "status": 404,
+        "message": "Index not found"
+      }),
+      status: Status::NotFound
+    }
+  }
+}
+
+#[post("/<index_name>/count?<q>", data="<input>")]
+fn count_items(index_name: String, input: Json<SearchOptions>, q: Option<String>) -> ApiResponse {
+  if let Some(shared) = INDEXES.get(&index_name) {
+    let index = shared.read().unwrap();
+    let data = input.into_inner();
+
+    // Counts every match, not just the max_candidates best
+    let found = get_items(&index, q.clone(), false, data.filter.as_ref(), true);
+    if let Err(message) = found {
+      return query_error(message);
+    }
+    let (items, _, _) = found.unwrap();
+
+    return ApiResponse {
+      json: json!({
+        "status": 200,
+        "query": q,
+        "count": items.len(),
+      }),
+      status: Status::Ok
+    }
+  }
+  else {
+    return ApiResponse {
+      json: json!({
+        "status": 404,
+        "message": "Index not found"
+      }),
+      status: Status::NotFound
+    }
+  }
+}
+
+// Completions of what is being typed, the words and, with complete_values,
+// the field values starting with prefix that are in the most documents
+#[get("/<index_name>/suggest?<prefix>&<take>")]
+fn get_suggestions(index_name: String, prefix: Option<String>, take: Option<usize>) -> ApiResponse {
+  if let Some(shared) = INDEXES.get(&index_name) {
+    let index = shared.read().unwrap();
+    let prefix = prefix.unwrap_or(String::from(""));
+    let take = cmp::min(take.unwrap_or(10), 100);
+
+    let words = index.completions.complete(&prefix, take);
+    let values = index.value_completions.complete(&prefix, take);
+
+    return ApiResponse {
+      json: json!({
+        "status": 200,
+        "prefix": prefix,
+        "words": words,
+        "values": values,
+      }),
+      status: Status::Ok
+    }
+  }
+  else {
+    return ApiResponse {
+      json: json!({
+        "status": 404,
+        "message": "Index not found"
+      }),
+      status: Status::NotFound
+    }
+  }
+}
+
+#[get("/<index_name>/doc/<id>")]
+fn get_item(index_name: String, id: String) -> ApiResponse {
+  if let Some(shared) = INDEXES.get(&index_name) {
+    let index = shared.read().unwrap();
+
+    if let Some(item) = index::get(&index, &id) {
+      return ApiResponse {
+        json: json!({
+          "status": 200,
+          "item": item,
+        }),
+        status: Status::Ok
+      }
+    }
+
+    return ApiResponse {
+      json: json!({
+        "status": 404,
+        "message": "Item not found"
+      }),
+      status: Status::NotFound
+    }
+  }
+  else {
+    return ApiResponse {
+      json: json!({
+        "status": 404,
+        "message": "Index not found"
+      }),
+      status: Status::NotFound
+    }
+  }
+}
+
+#[head("/<index_name>/doc/<id>")]
+fn item_exists(index_name: String, id: String) -> Status {
+  if let Some(shared) = INDEXES.get(&index_name) {
+    let index = shared.read().unwrap();
+
+    if index.id_map.contains_key(&id) {
+      return Status::Ok;
+    }
+  }
+  return Status::NotFound;
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct MultiGet {
+  ids: Vec<String>,
+}
+
+#[post("/<index_name>/mget", data="<input>")]
+fn get_items_by_id(index_name: String, input: Json<MultiGet>) -> ApiResponse {
+  if let Some(shared) = INDEXES.get(&index_name) {
+    let index = shared.read().unwrap();
+    let data = input.into_inner();
+
+    let mut items: Vec<Value> = Vec::new();
+    let mut missing: Vec<String> = Vec::new();
+
+    for id in data.ids {
+      match index::get(&index, &id) {
+        Some(item) => items.push(item.clone()),
+        None => missing.push(id),
+      }
+    }
+
+    return ApiResponse {
+      json: json!({
+        "status": 200,
+        "items": items,
+        "missing": missing,
       }),
       status: Status::Ok
     }
@@ -384,25 +781,33 @@ fn search_items(index_name: String, input: Json<SearchOptions>, q: Option<String
 
 #[derive(Clone, Serialize, Deserialize)]
 struct BulkDelete {
-  items: Vec<String>,
+  items: Vec<Value>,
+  atomic: Option<bool>,
 }
 
 #[delete("/<index_name>", data="<input>")]
 fn delete_items(index_name: String, input: Json<BulkDelete>) -> ApiResponse {
-  let mut indexes = INDEXES.lock().unwrap();
-  
-  if indexes.contains_key(&index_name) {
-    let data = input.into_inner();
-    let mut index = indexes.get_mut(&index_name).unwrap();
-    
-    for id in data.items {
-      index::remove(&mut index, id);
+  let data = input.into_inner();
+  let atomic = data.atomic.unwrap_or(false);
+  let op = wal::Operation::DeleteItems { index: index_name, ids: data.items, atomic };
+
+  let results = INDEXES.write(op);
+  if let Err(err) = results {
+    return wal_error(err);
+  }
+
+  if let Some(results) = results.unwrap() {
+    let errors = results.iter().any(|x| x.status == index::ItemStatus::Error);
+    if errors && atomic {
+      return bulk_rejected(results);
     }
 
     return ApiResponse {
       json: json!({
         "status": 200,
-        "message": "Items deleted"
+        "message": "Items deleted",
+        "errors": errors,
+        "results": results
       }),
       status: Status::Ok
     }
@@ -421,22 +826,27 @@ fn delete_items(index_name: String, input: Json<BulkDelete>) -> ApiResponse {
 
 #[patch("/<index_name>", data="<input>")]
 fn update_item(index_name: String, input: Json<BulkImport>) -> ApiResponse {
-  let mut indexes = INDEXES.lock().unwrap();
-  
-  if indexes.contains_key(&index_name) {
-    let data = input.into_inner();
-    let mut index = indexes.get_mut(&index_name).unwrap();
-    for item in data.items {
-      index::update(
-        &mut index, 
-        item,
-      );
+  let data = input.into_inner();
+  let atomic = data.atomic.unwrap_or(false);
+  let op = wal::Operation::UpdateItems { index: index_name, items: data.items, atomic };
+
+  let results = INDEXES.write(op);
+  if let Err(err) = results {
+    return wal_error(err);
+  }
+
+  if let Some(results) = results.unwrap() {
+    let errors = results.iter().any(|x| x.status == index::ItemStatus::Error);
+    if errors && atomic {
+      return bulk_rejected(results);
     }
 
     return ApiResponse {
       json: json!({
         "status": 200,
-        "message": "Items updated"
+        "message": "Items updated",
+        "errors": errors,
+        "results": results
       }),
       status: Status::Ok
     }
@@ -454,22 +864,28 @@ fn update_item(index_name: String, input: Json<BulkImport>) -> ApiResponse {
 
 #[post("/<index_name>", data="<input>")]
 fn post_items(index_name: String, input: Json<BulkImport>) -> ApiResponse {
-  let mut indexes = INDEXES.lock().unwrap();
   let data = input.into_inner();
+  let on_conflict = data.on_conflict.unwrap_or_default();
+  let atomic = data.atomic.unwrap_or(false);
+  let op = wal::Operation::AddItems { index: index_name, items: data.items, on_conflict, atomic };
 
-  if indexes.contains_key(&index_name) {
-    let mut index = indexes.get_mut(&index_name).unwrap();
-    for item in data.items {
-      index::add_object(
-        &mut index, 
-        item,
-      );
+  let results = INDEXES.write(op);
+  if let Err(err) = results {
+    return wal_error(err);
+  }
+
+  if let Some(results) = results.unwrap() {
+    let errors = results.iter().any(|x| x.status == index::ItemStatus::Error);
+    if errors && atomic {
+      return bulk_rejected(results);
     }
 
     return ApiResponse {
       json: json!({
         "status": 200,
-        "message": "Items added"
+        "message": "Items added",
+        "errors": errors,
+        "results": results
       }),
       status: Status::Ok
     }
@@ -487,15 +903,25 @@ fn post_items(index_name: String, input: Json<BulkImport>) -> ApiResponse {
 
 #[derive(Clone, Serialize, Deserialize)]
 struct CreateIndex {
-  fields: Vec<String>,
+  fields: Vec<index::Field>,
+  settings: Option<index::Settings>,
 }
 
 #[put("/<index_name>", data="<input>")]
 fn create_index(index_name: String, input: Json<CreateIndex>) -> ApiResponse {
-  let mut indexes = INDEXES.lock().unwrap();
   let data = input.into_inner();
 
-  if indexes.contains_key(&index_name) {
+  let mut settings = data.settings.unwrap_or_default();
+  if let Err(message) = index::validate_settings(&data.fields, &mut settings) {
+    return settings_error(message);
+  }
+
+  let created = INDEXES.create(index_name, data.fields, settings);
+  if let Err(err) = created {
+    return wal_error(err);
+  }
+
+  if !created.unwrap() {
     return ApiResponse {
       json: json!({
         "status": 409,
@@ -506,8 +932,6 @@ fn create_index(index_name: String, input: Json<CreateIndex>) -> ApiResponse {
     }
   }
   else {
-    let index = index::create(data.fields);
-    indexes.insert(index_name, index);
     return ApiResponse {
       json: json!({
         "status": 200,
@@ -518,12 +942,129 @@ fn create_index(index_name: String, input: Json<CreateIndex>) -> ApiResponse {
   }
 }
 
+#[put("/<index_name>/settings", data="<input>")]
+fn update_settings(index_name: String, input: Json<index::Settings>) -> ApiResponse {
+  let mut settings = input.into_inner();
+
+  // Fields never change, they can be checked before the update is logged
+  if let Some(shared) = INDEXES.get(&index_name) {
+    let fields = shared.read().unwrap().fields.clone();
+    if let Err(message) = index::validate_settings(&fields, &mut settings) {
+      return settings_error(message);
+    }
+  }
+
+  let op = wal::Operation::UpdateSettings { index: index_name, settings };
+
+  let found = INDEXES.write(op);
+  if let Err(err) = found {
+    return wal_error(err);
+  }
+
+  if found.unwrap().is_some() {
+    return ApiResponse {
+      json: json!({
+        "status": 200,
+        "message": "Settings updated"
+      }),
+      status: Status::Ok
+    }
+  }
+  else {
+    return ApiResponse {
+      json: json!({
+        "status": 404,
+        "message": "Index not found"
+      }),
+      status: Status::NotFound
+    }
+  }
+}
+
+#[get("/<index_name>/synonyms")]
+fn get_synonyms(index_name: String) -> ApiResponse {
+  if let Some(shared) = INDEXES.get(&index_name) {
+    let index = shared.read().unwrap();
+    return ApiResponse {
+      json: json!({
+        "status": 200,
+        "synonyms": index.synonyms
+      }),
+      status: Status::Ok
+    }
+  }
+  else {
+    return ApiResponse {
+      json: json!({
+        "status": 404,
+        "message": "Index not found",
+        "error": true
+      }),
+      status: Status::NotFound
+    }
+  }
+}
+
+#[derive(Deserialize)]
+struct UpdateSynonyms {
+  synonyms: Vec<synonyms::Synonyms>,
+}
+
+// Replaces all synonyms of the index, they apply to the next query
+#[put("/<index_name>/synonyms", data="<input>")]
+fn update_synonyms(index_name: String, input: Json<UpdateSynonyms>) -> ApiResponse {
+  let data = input.into_inner();
+  if let Err(message) = synonyms::validate(&data.synonyms) {
+    return ApiResponse {
+      json: json!({
+        "status": 400,
+        "message": format!("Invalid synonyms: {}", message),
+        "error": true
+      }),
+      status: Status::BadRequest
+    }
+  }
+
+  let op = wal::Operation::UpdateSynonyms { index: index_name, synonyms: data.synonyms };
+
+  let found = INDEXES.write(op);
+  if let Err(err) = found {
+    return wal_error(err);
+  }
+
+  if found.unwrap().is_some() {
+    return ApiResponse {
+      json: json!({
+        "status": 200,
+        "message": "Synonyms updated"
+      }),
+      status: Status::Ok
+    }
+  }
+  else {
+    return ApiResponse {
+      json: json!({
+        "status": 404,
+        "message": "Index not found"
+      }),
+      status: Status::NotFound
+    }
+  }
+}
+
 #[delete("/<index_name>/delete", rank = 0)]
 fn delete_index(index_name: String) -> Status {
-  let mut indexes = INDEXES.lock().unwrap();
+  let deleted = INDEXES.delete(index_name.clone());
+  if deleted.is_err() {
+    return Status::InternalServerError;
+  }
 
-  if indexes.contains_key(&index_name) {
-    indexes.remove(&index_name);
+  if deleted.unwrap() {
+    if let Some(dir) = data_dir() {
+      if let Err(err) = persist::remove(&dir, &index_name) {
+        println!("Failed to remove snapshot of index '{}': {}", index_name, err);
+      }
+    }
     return Status::Ok;
   }
   return Status::NotFound;
@@ -531,11 +1072,14 @@ fn delete_index(index_name: String) -> Status {
 
 #[delete("/<index_name>/clear", rank = 0)]
 fn clear_index(index_name: String) -> Status {
-  let mut indexes = INDEXES.lock().unwrap();
+  let op = wal::Operation::ClearIndex { index: index_name };
 
-  if indexes.contains_key(&index_name) {
-    let index = indexes.get_mut(&index_name).unwrap();
-    index::clear(index);
+  let found = INDEXES.write(op);
+  if found.is_err() {
+    return Status::InternalServerError;
+  }
+
+  if found.unwrap().is_some() {
     return Status::Ok;
   }
   return Status::NotFound;
@@ -543,23 +1087,29 @@ fn clear_index(index_name: String) -> Status {
 
 #[delete("/")]
 fn clear_all() -> Status {
-  let mut indexes = INDEXES.lock().unwrap();
-  indexes.clear();
-  indexes.shrink_to_fit();
+  if INDEXES.clear().is_err() {
+    return Status::InternalServerError;
+  }
+
+  if let Some(dir) = data_dir() {
+    if let Err(err) = persist::remove_all(&dir) {
+      println!("Failed to remove snapshots: {}", err);
+    }
+  }
   return Status::Ok;
 }
 
 #[get("/<index_name>")]
 fn get_index(index_name: String) -> ApiResponse {
-  let indexes = INDEXES.lock().unwrap();
-
-  if indexes.contains_key(&index_name) {
-    let index = indexes.get(&index_name).unwrap();
+  if let Some(shared) = INDEXES.get(&index_name) {
+    let index = shared.read().unwrap();
     return ApiResponse {
       json: json!({
         "status": 200,
         "items_count": index.items.len(),
         "tokens_count": index.token_scoring.len(),
+        "memory": index::memory_usage(&index),
+        "settings": index.settings,
       }),
       status: Status::Ok
     }
@@ -613,6 +1163,9 @@ fn main() {
 
   config.port = 8001;
 
+  let mut snapshot_interval = 60;
+  let mut fsync = wal::FsyncPolicy::Always;
+
   for (i, arg) in args.iter().enumerate() {
     if arg.cmp(&String::from("--port")) == std::cmp::Ordering::Equal {
       let port_num = args[i + 1].parse();
@@ -620,12 +1173,58 @@ fn main() {
         config.port = port_num.unwrap();
       }
     }
+    else if arg.cmp(&String::from("--data-dir")) == std::cmp::Ordering::Equal {
+      *DATA_DIR.lock().unwrap() = Some(PathBuf::from(&args[i + 1]));
+    }
+    else if arg.cmp(&String::from("--snapshot-interval")) == std::cmp::Ordering::Equal {
+      let secs = args[i + 1].parse();
+      if !secs.is_err() {
+        snapshot_interval = secs.unwrap();
+      }
+    }
+    else if arg.cmp(&String::from("--fsync")) == std::cmp::Ordering::Equal {
+      match wal::FsyncPolicy::parse(&args[i + 1]) {
+        Some(policy) => fsync = policy,
+        None => println!("Unknown fsync policy '{}', expected always, never or an interval in ms", args[i + 1]),
+      }
+    }
+  }
+
+  if let Some(dir) = data_dir() {
+    if let Err(err) = INDEXES.restore(&dir, fsync) {
+      println!("Failed to restore indexes from {}: {}", dir.display(), err);
+      std::process::exit(1);
+    }
+
+    if let wal::FsyncPolicy::Interval(ms) = fsync {
+      thread::spawn(move || {
+        loop {
+          thread::sleep(Duration::from_millis(cmp::max(1, ms)));
+          if let Err(err) = INDEXES.sync_log() {
+            println!("Failed to sync operation log: {}", err);
+          }
+        }
+      });
+    }
+
+    thread::spawn(move || {
+      loop {
+        thread::sleep(Duration::from_secs(cmp::max(1, snapshot_interval)));
+        snapshot_indexes();
+      }
+    });
+
+    ctrlc::set_handler(|| {
+      println!("Shutting down, writing snapshots");
+      snapshot_indexes();
+      std::process::exit(0);
+    }).expect("Failed to install shutdown handler");
   }
 
   let app = rocket::custom(config);
 
   app
     .mount("/", routes![hello])
-    .mount("/index", routes![get_times, delete_index, clear_index, clear_all, delete_items, create_index, get_index, post_items, update_item, search_items])
+    .mount("/index", routes![get_times, delete_index, clear_index, clear_all, delete_items, create_index, get_index, post_items, update_item, search_items, update_settings, get_synonyms, update_synonyms, count_items, get_suggestions, get_item, item_exists, get_items_by_id])
     .launch();
 }
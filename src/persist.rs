@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use crate::index;
+use crate::index::Index;
+
+#[derive(Serialize)]
+struct SnapshotRef<'a> {
+  name: &'a str,
+  index: &'a Index,
+}
+
+#[derive(Deserialize)]
+struct Snapshot {
+  name: String,
+  index: Index,
+}
+
+// Index names come straight from the URL, so the file name is derived
+// from a hash and the real name is stored inside the snapshot
+pub fn snapshot_path(dir: &Path, name: &str) -> PathBuf {
+  dir.join(format!("{:x}.snapshot", md5::compute(name)))
+}
+
+pub fn save(dir: &Path, name: &str, index: &Index) -> io::Result<()> {
+  fs::create_dir_all(dir)?;
+
+  let path = snapshot_path(dir, name);
+  let tmp_path = path.with_extension("snapshot.tmp");
+
+  {
+    let file = File::create(&tmp_path)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, &SnapshotRef { name, index })?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+  }
+
+  // Rename is atomic, so a crash never leaves a half written snapshot behind
+  fs::rename(&tmp_path, &path)?;
+  return Ok(());
+}
+
+pub fn remove(dir: &Path, name: &str) -> io::Result<()> {
+  let path = snapshot_path(dir, name);
+  if path.exists() {
+    fs::remove_file(path)?;
+  }
+  return Ok(());
+}
+
+pub fn remove_all(dir: &Path) -> io::Result<()> {
+  if !dir.exists() {
+    return Ok(());
+  }
+
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    if path.extension().map_or(false, |ext| ext == "snapshot") {
+      fs::remove_file(path)?;
+    }
+  }
+  return Ok(());
+}
+
+// Removes snapshots of indexes that no longer exist, e.g. when the process
+// died between logging a delete and removing the file
+pub fn prune(dir: &Path, names: &[&String]) -> io::Result<()> {
+  if !dir.exists() {
+    return Ok(());
+  }
+
+  let keep: Vec<PathBuf> = names.iter().map(|name| snapshot_path(dir, name)).collect();
+
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    if path.extension().map_or(false, |ext| ext == "snapshot") && !keep.contains(&path) {
+      fs::remove_file(path)?;
+    }
+  }
+  return Ok(());
+}
+
+pub fn load_all(dir: &Path) -> io::Result<HashMap<String, Index>> {
+  let mut indexes = HashMap::new();
+
+  if !dir.exists() {
+    return Ok(indexes);
+  }
+
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    if !path.extension().map_or(false, |ext| ext == "snapshot") {
+      continue;
+    }
+
+    let reader = BufReader::new(File::open(&path)?);
+    let mut snapshot: Snapshot = serde_json::from_reader(reader)?;
+    if index::needs_reindex(&snapshot.index) {
+      println!("Reindexing '{}' from an older snapshot format", snapshot.name);
+      index::reindex(&mut snapshot.index);
+    } else {
+      index::rebuild_doc_tokens(&mut snapshot.index);
+      index::rebuild_completions(&mut snapshot.index);
+    }
+    indexes.insert(snapshot.name, snapshot.index);
+  }
+
+  return Ok(indexes);
+}
@@ -0,0 +1,639 @@
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::fmt;
+use std::mem;
+use std::sync::Arc;
+use serde::de::{Deserialize, Deserializer, MapAccess, Visitor};
+use serde::ser::{Serialize, Serializer};
+
+// (id, field, term frequency)
+pub type Posting = (u32, u8, u8);
+
+// The postings of one term, sorted by id. Every posting is stored as the
+// varint encoded difference to the previous id, followed by the field and
+// term frequency bytes, so most postings take 3 bytes. Lists of words also
+// store the positions of the word in the field: their count, then the
+// varint encoded difference of each to the one before.
+#[derive(Default)]
+pub struct PostingList {
+  data: Vec<u8>,
+  len: u32,
+  // Number of distinct documents
+  docs: u32,
+  // Id of the last posting, new documents are appended after it
+  last: u32,
+  positional: bool,
+  // (id, byte offset, id of the posting before) of the first posting of a
+  // document about every SKIP_INTERVAL postings, so updates only decode
+  // the part of the list around their document
+  skips: Vec<(u32, u32, u32)>,
+  // Postings appended since the last skip
+  since_skip: u32,
+}
+
+const SKIP_INTERVAL: u32 = 64;
+
+// A whole posting list decoded for a query. The positions of the posting
+// at index i are positions[offsets[i]..offsets[i + 1]], offsets is empty
+// for lists without positions.
+pub struct DecodedList {
+  pub postings: Vec<Posting>,
+  pub offsets: Vec<usize>,
+  pub positions: Vec<u32>,
+}
+
+impl DecodedList {
+  pub fn positions_of(&self, i: usize) -> &[u32] {
+    if self.offsets.len() == 0 {
+      return &[];
+    }
+    &self.positions[self.offsets[i]..self.offsets[i + 1]]
+  }
+}
+
+fn write_varint(data: &mut Vec<u8>, mut value: u32) {
+  while value >= 0x80 {
+    data.push((value as u8) | 0x80);
+    value >>= 7;
+  }
+  data.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> u32 {
+  let mut value = 0;
+  let mut shift = 0;
+  loop {
+    let byte = data[*pos];
+    *pos += 1;
+    value |= ((byte & 0x7f) as u32) << shift;
+    if byte < 0x80 {
+      return value;
+    }
+    shift += 7;
+  }
+}
+
+// Appends a posting following the one with id `previous`
+fn write_posting(data: &mut Vec<u8>, positional: bool, posting: Posting, previous: u32, positions: &[u32]) {
+  write_varint(data, posting.0 - previous);
+  data.push(posting.1);
+  data.push(posting.2);
+
+  if positional {
+    write_varint(data, positions.len() as u32);
+    let mut last = 0;
+    for position in positions {
+      write_varint(data, position - last);
+      last = *position;
+    }
+  }
+}
+
+pub struct PostingIter<'a> {
+  data: &'a [u8],
+  pos: usize,
+  last: u32,
+  positional: bool,
+  // Positions of the last posting returned, only read when asked for
+  positions_start: usize,
+}
+
+impl<'a> PostingIter<'a> {
+  fn skip_positions(&mut self) {
+    if self.positional {
+      let count = read_varint(self.data, &mut self.pos);
+      for _ in 0..count {
+        read_varint(self.data, &mut self.pos);
+      }
+    }
+  }
+
+  // Positions of the posting last returned by next
+  fn positions(&self, into: &mut Vec<u32>) {
+    let mut pos = self.positions_start;
+    let count = read_varint(self.data, &mut pos);
+    let mut last = 0;
+    for _ in 0..count {
+      last += read_varint(self.data, &mut pos);
+      into.push(last);
+    }
+  }
+}
+
+impl<'a> Iterator for PostingIter<'a> {
+  type Item = Posting;
+
+  fn next(&mut self) -> Option<Posting> {
+    if self.pos >= self.data.len() {
+      return None;
+    }
+    self.last += read_varint(self.data, &mut self.pos);
+    let posting = (self.last, self.data[self.pos], self.data[self.pos + 1]);
+    self.pos += 2;
+    self.positions_start = self.pos;
+    self.skip_positions();
+    return Some(posting);
+  }
+}
+
+impl PostingList {
+  // Expects entries sorted by id
+  fn from_entries(positional: bool, entries: &[(Posting, Vec<u32>)]) -> PostingList {
+    let mut list = PostingList { positional, ..PostingList::default() };
+    for (posting, positions) in entries {
+      list.push(*posting, positions);
+    }
+    list.data.shrink_to_fit();
+    return list;
+  }
+
+  fn push(&mut self, posting: Posting, positions: &[u32]) {
+    if self.len == 0 || posting.0 != self.last {
+      self.docs += 1;
+      if self.since_skip >= SKIP_INTERVAL {
+        self.skips.push((posting.0, self.data.len() as u32, self.last));
+        self.since_skip = 0;
+      }
+    }
+    write_posting(&mut self.data, self.positional, posting, self.last, positions);
+    self.last = posting.0;
+    self.len += 1;
+    self.since_skip += 1;
+  }
+
+  // Byte offsets where the postings of iid start and end, their count and
+  // the id of the posting before them. Without postings of iid both
+  // offsets are where they would go. Decoding starts at the last skip
+  // before iid.
+  fn doc_range(&self, iid: u32) -> (usize, usize, u32, u32) {
+    let (mut start, mut before) = match self.skips.partition_point(|x| x.0 <= iid) {
+      0 => (0, 0),
+      i => (self.skips[i - 1].1 as usize, self.skips[i - 1].2),
+    };
+    let mut iter = PostingIter { data: &self.data, pos: start, last: before, positional: self.positional, positions_start: 0 };
+    let mut end = start;
+    let mut count = 0;
+    while let Some(posting) = iter.next() {
+      if posting.0 > iid {
+        break;
+      }
+      if posting.0 < iid {
+        start = iter.pos;
+        before = posting.0;
+      } else {
+        count += 1;
+      }
+      end = iter.pos;
+    }
+    return (start, end, count, before);
+  }
+
+  // Replaces the bytes from start to end with `bytes` and re-encodes the
+  // difference of the posting after them, which was to `old_last` and is
+  // now to `new_last`. The rest of the list is moved, not decoded. Skips
+  // into the replaced bytes move to the posting after them.
+  fn splice(&mut self, start: usize, end: usize, old_last: u32, mut bytes: Vec<u8>, new_last: u32) {
+    let inserted = bytes.len();
+    let mut next = end;
+    let mut next_id = None;
+    if end < self.data.len() {
+      let id = old_last + read_varint(&self.data, &mut next);
+      write_varint(&mut bytes, id - new_last);
+      next_id = Some(id);
+    }
+    let length = bytes.len();
+    self.data.splice(start..next, bytes);
+
+    let first = self.skips.partition_point(|x| (x.1 as usize) < start);
+    let after = self.skips.partition_point(|x| (x.1 as usize) <= end);
+    for skip in self.skips[after..].iter_mut() {
+      skip.1 = (skip.1 as usize - (next - start) + length) as u32;
+    }
+    let moved = match next_id {
+      Some(id) if after > first => Some((id, (start + inserted) as u32, new_last)),
+      _ => None,
+    };
+    self.skips.splice(first..after, moved);
+  }
+
+  pub fn iter(&self) -> PostingIter<'_> {
+    PostingIter { data: &self.data, pos: 0, last: 0, positional: self.positional, positions_start: 0 }
+  }
+
+  pub fn to_vec(&self) -> Vec<Posting> {
+    self.iter().collect()
+  }
+
+  fn entries(&self) -> Vec<(Posting, Vec<u32>)> {
+    let mut entries = Vec::with_capacity(self.len());
+    let mut iter = self.iter();
+    while let Some(posting) = iter.next() {
+      let mut positions = Vec::new();
+      if self.positional {
+        iter.positions(&mut positions);
+      }
+      entries.push((posting, positions));
+    }
+    return entries;
+  }
+
+  pub fn decode(&self) -> DecodedList {
+    let mut decoded = DecodedList {
+      postings: Vec::with_capacity(self.len()),
+      offsets: Vec::new(),
+      positions: Vec::new(),
+    };
+
+    let mut iter = self.iter();
+    while let Some(posting) = iter.next() {
+      decoded.postings.push(posting);
+      if self.positional {
+        decoded.offsets.push(decoded.positions.len());
+        iter.positions(&mut decoded.positions);
+      }
+    }
+    if self.positional {
+      decoded.offsets.push(decoded.positions.len());
+    }
+
+    return decoded;
+  }
+
+  pub fn len(&self) -> usize {
+    self.len as usize
+  }
+
+  pub fn doc_freq(&self) -> usize {
+    self.docs as usize
+  }
+
+  // Returns true when this is the first posting of the document
+  fn insert(&mut self, posting: Posting, positions: &[u32]) -> bool {
+    if self.len == 0 || posting.0 >= self.last {
+      let new_doc = self.len == 0 || posting.0 != self.last;
+      self.push(posting, positions);
+      return new_doc;
+    }
+
+    // Only updated documents land in the middle, after the postings the
+    // document already has so fields stay in order
+    let (start, end, _, before) = self.doc_range(posting.0);
+    let new_doc = start == end;
+    let previous = if new_doc { before } else { posting.0 };
+    let mut bytes = Vec::new();
+    write_posting(&mut bytes, self.positional, posting, previous, positions);
+    self.splice(end, end, previous, bytes, posting.0);
+
+    self.len += 1;
+    if new_doc {
+      self.docs += 1;
+    }
+    return new_doc;
+  }
+
+  fn remove_doc(&mut self, iid: u32) {
+    let (start, end, count, before) = self.doc_range(iid);
+    if count == 0 {
+      return;
+    }
+    if end == self.data.len() {
+      self.last = before;
+    }
+    self.splice(start, end, iid, Vec::new(), before);
+    self.len -= count;
+    self.docs -= 1;
+  }
+
+  fn memory_usage(&self) -> usize {
+    mem::size_of::<PostingList>() + self.data.capacity() + self.skips.capacity() * mem::size_of::<(u32, u32, u32)>()
+  }
+}
+
+// Term dictionary and posting lists. Every term is allocated once and
+// shared between the lookup map and the id to term table; documents refer
+// to the terms they are indexed under by id. The map is sorted so terms
+// can be walked from a prefix on.
+#[derive(Default)]
+pub struct Postings {
+  term_ids: BTreeMap<Arc<str>, u32>,
+  terms: Vec<Option<Arc<str>>>,
+  lists: Vec<PostingList>,
+  // Ids of removed terms, reused for new ones
+  free: Vec<u32>,
+  // Loaded from a snapshot written before words had positions, the index
+  // has to be rebuilt from its documents
+  pub missing_positions: bool,
+}
+
+impl Postings {
+  pub fn get(&self, term: &str) -> Option<&PostingList> {
+    self.term_ids.get(term).map(|id| &self.lists[*id as usize])
+  }
+
+  // Number of terms
+  pub fn len(&self) -> usize {
+    self.term_ids.len()
+  }
+
+  // Terms from start on, in order
+  pub fn terms_from(&self, start: &str) -> impl Iterator<Item = (&str, &PostingList)> {
+    self.term_ids
+      .range::<str, _>((Bound::Included(start), Bound::Unbounded))
+      .map(move |(term, id)| (&**term, &self.lists[*id as usize]))
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (u32, &PostingList)> {
+    self.terms
+      .iter()
+      .zip(self.lists.iter())
+      .enumerate()
+      .filter(|(_, (term, _))| term.is_some())
+      .map(|(id, (_, list))| (id as u32, list))
+  }
+
+  // The list of a new term stores positions when `positional` is set
+  fn term_id(&mut self, term: &str, positional: bool) -> u32 {
+    if let Some(id) = self.term_ids.get(term) {
+      return *id;
+    }
+
+    let term: Arc<str> = Arc::from(term);
+    let list = PostingList { positional, ..PostingList::default() };
+    let id = match self.free.pop() {
+      Some(id) => {
+        self.terms[id as usize] = Some(term.clone());
+        self.lists[id as usize] = list;
+        id
+      }
+      None => {
+        self.terms.push(Some(term.clone()));
+        self.lists.push(list);
+        (self.terms.len() - 1) as u32
+      }
+    };
+    self.term_ids.insert(term, id);
+    return id;
+  }
+
+  // Returns the term id when this is the first posting of the document for
+  // that term. Positions are only kept for terms first added with some.
+  pub fn insert(&mut self, term: &str, posting: Posting, positions: &[u32]) -> Option<u32> {
+    let id = self.term_id(term, positions.len() > 0);
+    if self.lists[id as usize].insert(posting, positions) {
+      return Some(id);
+    }
+    return None;
+  }
+
+  pub fn remove_doc(&mut self, term_id: u32, iid: u32) {
+    let list = &mut self.lists[term_id as usize];
+    list.remove_doc(iid);
+
+    if list.len() == 0 {
+      if let Some(term) = self.terms[term_id as usize].take() {
+        self.term_ids.remove(&term);
+        self.free.push(term_id);
+      }
+    }
+  }
+
+  // Approximate heap usage of the term dictionary and the posting lists
+  pub fn memory_usage(&self) -> (usize, usize) {
+    let entry = mem::size_of::<(Arc<str>, u32)>() + 1;
+    let terms_bytes = self.term_ids.len() * entry
+      + self.terms.capacity() * mem::size_of::<Option<Arc<str>>>()
+      + self.free.capacity() * mem::size_of::<u32>()
+      + self.term_ids.keys().map(|x| x.len() + 2 * mem::size_of::<usize>()).sum::<usize>();
+
+    let postings_bytes = self.lists.capacity() * mem::size_of::<PostingList>()
+      + self.lists.iter().map(|x| x.memory_usage() - mem::size_of::<PostingList>()).sum::<usize>();
+
+    return (terms_bytes, postings_bytes);
+  }
+}
+
+// A posting in a snapshot, [id, field, tf] or [id, field, tf, positions]
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum StoredPosting {
+  Plain(u32, u8, u8),
+  Positional(u32, u8, u8, Vec<u32>),
+}
+
+// Snapshots keep the plain format, a map from term to its postings
+impl Serialize for Postings {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_map(
+      self.term_ids
+        .iter()
+        .map(|(term, id)| {
+          let list = &self.lists[*id as usize];
+          let stored: Vec<StoredPosting> = list
+            .entries()
+            .into_iter()
+            .map(|(x, positions)| {
+              if list.positional {
+                StoredPosting::Positional(x.0, x.1, x.2, positions)
+              } else {
+                StoredPosting::Plain(x.0, x.1, x.2)
+              }
+            })
+            .collect();
+          (&**term, stored)
+        })
+    )
+  }
+}
+
+struct PostingsVisitor;
+
+impl<'de> Visitor<'de> for PostingsVisitor {
+  type Value = Postings;
+
+  fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+    formatter.write_str("a map from term to postings")
+  }
+
+  fn visit_map<A: MapAccess<'de>>(self, mut access: A) -> Result<Postings, A::Error> {
+    let mut postings = Postings::default();
+
+    while let Some((term, stored)) = access.next_entry::<String, Vec<StoredPosting>>()? {
+      if stored.len() == 0 {
+        continue;
+      }
+
+      let positional = match stored[0] {
+        StoredPosting::Positional(..) => true,
+        StoredPosting::Plain(..) => false,
+      };
+      // Trigram keys start with '#', every other term is a word
+      if !positional && !term.starts_with('#') {
+        postings.missing_positions = true;
+      }
+
+      let mut entries: Vec<(Posting, Vec<u32>)> = stored
+        .into_iter()
+        .map(|x| match x {
+          StoredPosting::Plain(iid, field, tf) => ((iid, field, tf), Vec::new()),
+          StoredPosting::Positional(iid, field, tf, positions) => ((iid, field, tf), positions),
+        })
+        .collect();
+      // Older snapshots did not keep updated documents in order. The sort
+      // is stable, so postings within a document keep their field order.
+      entries.sort_by_key(|x| (x.0).0);
+
+      let id = postings.term_id(&term, positional);
+      postings.lists[id as usize] = PostingList::from_entries(positional, &entries);
+    }
+
+    return Ok(postings);
+  }
+}
+
+impl<'de> Deserialize<'de> for Postings {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Postings, D::Error> {
+    deserializer.deserialize_map(PostingsVisitor)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn entries(positional: bool) -> Vec<(Posting, Vec<u32>)> {
+    let positions = |x: &[u32]| if positional { x.to_vec() } else { Vec::new() };
+    vec![
+      ((1, 0, 1), positions(&[0])),
+      ((5, 0, 2), positions(&[1, 4])),
+      ((5, 1, 1), positions(&[2])),
+      ((200, 0, 1), positions(&[130])),
+      ((70000, 2, 1), positions(&[3])),
+    ]
+  }
+
+  #[test]
+  fn varint_round_trip() {
+    let values = [0, 1, 127, 128, 16383, 16384, 2097151, 2097152, u32::MAX];
+    let mut data = Vec::new();
+    for value in values.iter() {
+      write_varint(&mut data, *value);
+    }
+    let mut pos = 0;
+    for value in values.iter() {
+      assert_eq!(read_varint(&data, &mut pos), *value);
+    }
+    assert_eq!(pos, data.len());
+  }
+
+  #[test]
+  fn decode_round_trip() {
+    for positional in [false, true].iter() {
+      let entries = entries(*positional);
+      let list = PostingList::from_entries(*positional, &entries);
+      assert_eq!(list.len(), 5);
+      assert_eq!(list.doc_freq(), 4);
+      assert_eq!(list.entries(), entries);
+    }
+  }
+
+  #[test]
+  fn insert_out_of_order() {
+    for positional in [false, true].iter() {
+      let entries = entries(*positional);
+      let expected = PostingList::from_entries(*positional, &entries);
+
+      // Documents pushed in order except 5, which is updated later
+      let mut list = PostingList { positional: *positional, ..PostingList::default() };
+      for i in [0, 3, 4, 1, 2].iter() {
+        let (posting, positions) = &entries[*i];
+        assert_eq!(list.insert(*posting, positions), *i != 2);
+      }
+      assert_eq!(list.data, expected.data);
+      assert_eq!(list.len(), 5);
+      assert_eq!(list.doc_freq(), 4);
+      assert_eq!(list.last, 70000);
+    }
+  }
+
+  #[test]
+  fn remove_doc() {
+    for positional in [false, true].iter() {
+      for iid in [1, 5, 200, 70000].iter() {
+        let mut entries = entries(*positional);
+        let mut list = PostingList::from_entries(*positional, &entries);
+        list.remove_doc(*iid);
+        entries.retain(|x| (x.0).0 != *iid);
+        let expected = PostingList::from_entries(*positional, &entries);
+
+        assert_eq!(list.data, expected.data);
+        assert_eq!(list.len(), expected.len());
+        assert_eq!(list.doc_freq(), 3);
+        assert_eq!(list.last, expected.last);
+      }
+
+      // Removing a document that isn't there changes nothing
+      let mut list = PostingList::from_entries(*positional, &entries(*positional));
+      list.remove_doc(6);
+      assert_eq!(list.len(), 5);
+      assert_eq!(list.entries(), entries(*positional));
+    }
+  }
+
+  // Every skip points at the first posting of its document
+  fn check_skips(list: &PostingList) {
+    let mut offset = 0;
+    for (i, skip) in list.skips.iter().enumerate() {
+      assert!(i == 0 || skip.1 > offset);
+      offset = skip.1;
+      let mut iter = PostingIter { data: &list.data, pos: skip.1 as usize, last: skip.2, positional: list.positional, positions_start: 0 };
+      assert_eq!(iter.next().map(|x| x.0), Some(skip.0));
+      assert!(skip.2 < skip.0);
+      assert!(list.iter().any(|x| x.0 == skip.2));
+    }
+  }
+
+  #[test]
+  fn skips_follow_updates() {
+    for positional in [false, true].iter() {
+      let positions = |x: u32| if *positional { vec![x, x + 3] } else { Vec::new() };
+      let mut entries: Vec<(Posting, Vec<u32>)> = Vec::new();
+      for iid in 1..1000 {
+        entries.push(((iid * 3, 0, 1), positions(iid)));
+        if iid % 4 == 0 {
+          entries.push(((iid * 3, 1, 2), positions(iid + 1)));
+        }
+      }
+      let mut list = PostingList::from_entries(*positional, &entries);
+      assert!(list.skips.len() > 10);
+      check_skips(&list);
+
+      // Documents at a skip, right after one, in between and the last one
+      let mut removed: Vec<u32> = list.skips.iter().take(5).map(|x| x.0).collect();
+      removed.extend(list.skips.iter().take(5).map(|x| x.0 + 3));
+      removed.extend(&[30, 300, 2997]);
+      for iid in removed.iter() {
+        list.remove_doc(*iid);
+        entries.retain(|x| (x.0).0 != *iid);
+        check_skips(&list);
+      }
+      // Updated documents land in the middle, also before a skip
+      for iid in list.skips.iter().take(3).map(|x| x.0 - 1).collect::<Vec<u32>>() {
+        list.insert((iid, 0, 1), &positions(iid));
+        let at = entries.iter().position(|x| (x.0).0 > iid).unwrap();
+        entries.insert(at, ((iid, 0, 1), positions(iid)));
+        check_skips(&list);
+      }
+      list.insert((removed[0], 2, 1), &positions(1));
+      let at = entries.iter().position(|x| (x.0).0 > removed[0]).unwrap();
+      entries.insert(at, ((removed[0], 2, 1), positions(1)));
+      check_skips(&list);
+
+      let expected = PostingList::from_entries(*positional, &entries);
+      assert_eq!(list.data, expected.data);
+      assert_eq!(list.entries(), entries);
+      assert_eq!(list.len(), expected.len());
+      assert_eq!(list.doc_freq(), expected.doc_freq());
+      assert_eq!(list.last, expected.last);
+    }
+  }
+}
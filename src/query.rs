@@ -0,0 +1,677 @@
+use crate::index;
+use crate::index::Index;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::mem;
+
+use crate::lp::Analyzer;
+use crate::synonyms;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Occur {
+  // Only adds to the score
+  Should,
+  // +term, and quoted phrases
+  Must,
+  // -term
+  MustNot,
+}
+
+// The words of a term as analyzed for some of the fields, with their
+// positions. Fields are None when all of them analyze it the same way.
+pub struct Words {
+  pub fields: Option<Vec<usize>>,
+  pub words: Vec<String>,
+  pub positions: Vec<u32>,
+}
+
+pub enum Node {
+  // One word, or several for terms like "e-mail" which then have to occur
+  // next to each other. No words at all for terms that are too short to be
+  // indexed as a word, those only count through their trigrams.
+  Term { text: String, words: Vec<Words>, field: Option<usize> },
+  // term*, any indexed word starting with the (unstemmed) prefix, which
+  // `words` holds as normalized for the fields
+  Prefix { prefix: String, words: Vec<Words> },
+  // Words that have to occur next to each other, in order. With a slop of
+  // N ("red dress"~N) they may be up to N positions apart, in any order.
+  Phrase { text: String, words: Vec<Words>, slop: Option<u32>, field: Option<usize> },
+  // Clauses next to each other, or in parentheses
+  Group(Vec<(Occur, Node)>),
+  // a OR b
+  Or(Vec<Node>),
+  // Terms, or any of the phrases (Node::Term) their synonyms expand to
+  Synonyms(Box<Node>, Vec<Node>),
+  // A synonym phrase with words too short to be indexed, like the "t" of
+  // "t-shirt", only found in the documents that spell it out
+  Within(Box<Node>, HashSet<u32>),
+}
+
+pub struct Query {
+  pub root: Node,
+  // The words of the query without operators, excluded terms or field
+  // names, what the fuzzy match works on
+  pub text: String,
+}
+
+impl Query {
+  // Leaves that add to the score, everything not excluded with '-'
+  pub fn positive_leaves(&self) -> Vec<&Node> {
+    let mut leaves = Vec::new();
+    collect_positive(&self.root, &mut leaves);
+    return leaves;
+  }
+
+  // Phrases of synonyms the positive leaves expand to
+  pub fn synonym_leaves(&self) -> Vec<&Node> {
+    let mut leaves = Vec::new();
+    collect_synonyms(&self.root, &mut leaves);
+    return leaves;
+  }
+
+  // Just words, as plain queries have always been
+  pub fn is_plain(&self) -> bool {
+    match &self.root {
+      Node::Group(clauses) => clauses.iter().all(|(occur, node)| {
+        *occur == Occur::Should && match node {
+          Node::Term { words, field: None, .. } => words.iter().all(|x| x.words.len() <= 1),
+          _ => false,
+        }
+      }),
+      _ => false,
+    }
+  }
+}
+
+fn collect_positive<'a>(node: &'a Node, leaves: &mut Vec<&'a Node>) {
+  match node {
+    Node::Group(clauses) => {
+      for (occur, child) in clauses {
+        if *occur != Occur::MustNot {
+          collect_positive(child, leaves);
+        }
+      }
+    }
+    Node::Or(branches) => {
+      for branch in branches {
+        collect_positive(branch, leaves);
+      }
+    }
+    Node::Synonyms(original, _) => collect_positive(original, leaves),
+    leaf => leaves.push(leaf),
+  }
+}
+
+fn collect_synonyms<'a>(node: &'a Node, leaves: &mut Vec<&'a Node>) {
+  match node {
+    Node::Group(clauses) => {
+      for (occur, child) in clauses {
+        if *occur != Occur::MustNot {
+          collect_synonyms(child, leaves);
+        }
+      }
+    }
+    Node::Or(branches) => {
+      for branch in branches {
+        collect_synonyms(branch, leaves);
+      }
+    }
+    Node::Synonyms(original, phrases) => {
+      collect_synonyms(original, leaves);
+      leaves.extend(phrases.iter());
+    }
+    _ => {}
+  }
+}
+
+struct Parser<'a> {
+  chars: Vec<char>,
+  pos: usize,
+  index: &'a Index,
+  analyzers: Vec<(Cow<'a, Analyzer>, Vec<usize>)>,
+}
+
+fn is_term_char(c: char) -> bool {
+  !c.is_whitespace() && c != '(' && c != ')' && c != '"'
+}
+
+impl<'a> Parser<'a> {
+  // Runs text through the analyzer of every field it applies to. Fields
+  // that end up with the same words share an entry.
+  fn analyze<F>(&self, field: Option<usize>, analyze: F) -> Vec<Words>
+    where F: Fn(&Analyzer) -> Vec<(String, u32)>
+  {
+    if let Some(field) = field {
+      let (words, positions) = analyze(&index::field_analyzer(self.index, field)).into_iter().unzip();
+      return vec![Words { fields: Some(vec![field]), words, positions }];
+    }
+
+    let mut analyzed: Vec<Words> = Vec::new();
+    for (analyzer, fields) in self.analyzers.iter() {
+      let (words, positions): (Vec<String>, Vec<u32>) = analyze(analyzer).into_iter().unzip();
+      match analyzed.iter_mut().find(|x| x.words == words && x.positions == positions) {
+        Some(existing) => existing.fields.as_mut().unwrap().extend(fields.iter()),
+        None => analyzed.push(Words { fields: Some(fields.clone()), words, positions }),
+      }
+    }
+
+    if analyzed.len() == 1 {
+      analyzed[0].fields = None;
+    }
+    return analyzed;
+  }
+
+  // Synonyms are matched on the terms of the first analyzer of the fields
+  fn terms(&self, text: &str, field: Option<usize>) -> Vec<String> {
+    match field {
+      Some(field) => index::field_analyzer(self.index, field).terms(text),
+      None => self.analyzers.first().map_or(Vec::new(), |x| x.0.terms(text)),
+    }
+  }
+
+  fn expand(&self, node: Node, expansions: &Vec<(&String, Vec<&String>)>) -> Node {
+    match node {
+      Node::Group(clauses) => Node::Group(self.expand_group(clauses, expansions)),
+      Node::Or(branches) => Node::Or(branches.into_iter().map(|x| self.expand(x, expansions)).collect()),
+      leaf => leaf,
+    }
+  }
+
+  // Terms next to each other that spell a synonym become one clause,
+  // either them or any of the phrases they expand to. Only plain terms
+  // are joined this way, +a +b stays as it is.
+  fn expand_group(&self, clauses: Vec<(Occur, Node)>, expansions: &Vec<(&String, Vec<&String>)>) -> Vec<(Occur, Node)> {
+    let keys: Vec<Option<(Option<usize>, Vec<String>)>> = clauses.iter().map(|(_, node)| match node {
+      Node::Term { text, field, .. } => Some((*field, self.terms(text, *field))),
+      _ => None,
+    }).collect();
+    let occurs: Vec<Occur> = clauses.iter().map(|x| x.0).collect();
+
+    // Synonym phrases as terms, for every field they're needed for
+    let mut sources: Vec<(Option<usize>, Vec<Vec<String>>)> = Vec::new();
+    for (field, _) in keys.iter().flatten() {
+      if !sources.iter().any(|x| x.0 == *field) {
+        sources.push((*field, expansions.iter().map(|x| self.terms(x.0, *field)).collect()));
+      }
+    }
+
+    let mut clauses = clauses.into_iter().map(|(occur, node)| (occur, self.expand(node, expansions)));
+    let mut expanded = Vec::new();
+    let mut i = 0;
+    while i < keys.len() {
+      let (length, phrases) = match &keys[i] {
+        Some((field, _)) => {
+          let field_sources = &sources.iter().find(|x| x.0 == *field).unwrap().1;
+          longest_synonym(&keys[i..], &occurs[i..], field_sources, expansions)
+        }
+        None => (0, Vec::new()),
+      };
+      if length == 0 {
+        expanded.push(clauses.next().unwrap());
+        i += 1;
+        continue;
+      }
+
+      let field = keys[i].as_ref().unwrap().0;
+      let mut run: Vec<(Occur, Node)> = clauses.by_ref().take(length).collect();
+      let occur = run[0].0;
+      let original = if length == 1 {
+        run.pop().unwrap().1
+      } else {
+        Node::Group(run.into_iter().map(|x| (Occur::Should, x.1)).collect())
+      };
+
+      let mut synonyms = Vec::new();
+      for phrase in phrases {
+        let words = self.analyze(field, |x| x.words(phrase));
+        // A phrase of stop words would match anything
+        if words.iter().all(|x| x.words.len() == 0) {
+          continue;
+        }
+        let term = Node::Term { text: phrase.clone(), words, field };
+        match index::spelled_out(self.index, phrase, field) {
+          Some(docs) => synonyms.push(Node::Within(Box::new(term), docs)),
+          None => synonyms.push(term),
+        }
+      }
+
+      expanded.push((occur, Node::Synonyms(Box::new(original), synonyms)));
+      i += length;
+    }
+    return expanded;
+  }
+
+  fn error<T>(&self, message: &str) -> Result<T, String> {
+    Err(format!("{} at position {}", message, self.pos))
+  }
+
+  fn peek(&self) -> Option<char> {
+    self.chars.get(self.pos).cloned()
+  }
+
+  fn skip_whitespace(&mut self) {
+    while self.peek().map_or(false, |c| c.is_whitespace()) {
+      self.pos += 1;
+    }
+  }
+
+  fn at_end_of_term(&self, offset: usize) -> bool {
+    self.chars.get(self.pos + offset).map_or(true, |c| c.is_whitespace() || *c == ')')
+  }
+
+  // A quote without a closing one, like in 12" pizza
+  fn at_lone_quote(&self) -> bool {
+    self.peek() == Some('"') && !self.chars[self.pos + 1..].contains(&'"')
+  }
+
+  // Skips punctuation that is just text, a '+' or '-' on its own like in
+  // "Impossible - Fallout" and lone quotes. Returns whether it skipped any.
+  fn skip_literals(&mut self) -> bool {
+    let mut skipped = false;
+    loop {
+      self.skip_whitespace();
+      let sign = self.peek().map_or(false, |c| c == '+' || c == '-') && self.at_end_of_term(1);
+      if !sign && !self.at_lone_quote() {
+        return skipped;
+      }
+      self.pos += 1;
+      skipped = true;
+    }
+  }
+
+  fn at_or(&self) -> bool {
+    let end = self.pos + 2;
+    end <= self.chars.len()
+      && self.chars[self.pos..end] == ['O', 'R']
+      && self.chars.get(end).map_or(true, |c| !is_term_char(*c) || *c == '(')
+  }
+
+  fn parse_or(&mut self) -> Result<Node, String> {
+    let mut branches = vec![self.parse_group()?];
+    while self.at_or() {
+      self.pos += 2;
+      branches.push(self.parse_group()?);
+    }
+
+    if branches.len() == 1 {
+      return Ok(branches.pop().unwrap());
+    }
+    return Ok(Node::Or(branches));
+  }
+
+  fn parse_group(&mut self) -> Result<Node, String> {
+    let mut clauses = Vec::new();
+    let mut skipped = false;
+
+    loop {
+      skipped = self.skip_literals() || skipped;
+      if self.peek().map_or(true, |c| c == ')') || self.at_or() {
+        break;
+      }
+      clauses.push(self.parse_clause()?);
+    }
+
+    // Nothing but punctuation is a group without clauses, like an empty
+    // query
+    if clauses.len() == 0 && !skipped {
+      return self.error("Expected a search term");
+    }
+    return Ok(Node::Group(clauses));
+  }
+
+  fn parse_clause(&mut self) -> Result<(Occur, Node), String> {
+    let mut occur = Occur::Should;
+
+    // A sign on its own was skipped as text by parse_group
+    if let Some(c) = self.peek().filter(|c| *c == '+' || *c == '-') {
+      self.pos += 1;
+      occur = if c == '+' { Occur::Must } else { Occur::MustNot };
+    }
+
+    let node = self.parse_primary(None)?;
+
+    // A quoted phrase has to occur unless it's excluded
+    if let (Node::Phrase { .. }, Occur::Should) = (&node, occur) {
+      occur = Occur::Must;
+    }
+    return Ok((occur, node));
+  }
+
+  fn parse_primary(&mut self, field: Option<usize>) -> Result<Node, String> {
+    match self.peek() {
+      Some('(') if field.is_none() => {
+        self.pos += 1;
+        let node = self.parse_or()?;
+        if self.peek() != Some(')') {
+          return self.error("Missing closing parenthesis");
+        }
+        self.pos += 1;
+        return Ok(node);
+      }
+      Some('(') => self.error("Field names only apply to a single term or phrase"),
+      Some('"') => self.parse_phrase(field),
+      Some(c) if is_term_char(c) => self.parse_term(field),
+      _ => self.error("Expected a search term"),
+    }
+  }
+
+  fn parse_phrase(&mut self, field: Option<usize>) -> Result<Node, String> {
+    self.pos += 1;
+
+    // A lone quote right after a sign or field name, the term after it is
+    // the text
+    let closing = self.chars[self.pos..].iter().position(|c| *c == '"');
+    if closing.is_none() {
+      if self.peek().map_or(false, is_term_char) {
+        return self.parse_term(field);
+      }
+      let words = self.analyze(field, |x| x.words(""));
+      return Ok(Node::Term { text: String::new(), words, field });
+    }
+
+    let end = self.pos + closing.unwrap();
+    let text: String = self.chars[self.pos..end].iter().collect();
+    self.pos = end + 1;
+
+    let mut slop = None;
+    if self.peek() == Some('~') {
+      self.pos += 1;
+      let digits_start = self.pos;
+      while self.peek().map_or(false, |c| c.is_ascii_digit()) {
+        self.pos += 1;
+      }
+      let digits: String = self.chars[digits_start..self.pos].iter().collect();
+      match digits.parse() {
+        Ok(n) => slop = Some(n),
+        Err(_) => return self.error("Expected a number after '~'"),
+      }
+    }
+
+    let words = self.analyze(field, |x| x.words(&text));
+    if words.iter().all(|x| x.words.len() == 0) {
+      return Ok(Node::Term { text, words, field });
+    }
+    return Ok(Node::Phrase { text, words, slop, field });
+  }
+
+  // The field a term starting at the current position is scoped to, when
+  // it starts with the name of a field and a colon followed by something
+  // to search for. Other colons, like in "Star Wars: A New Hope" or
+  // "10:30", are just text.
+  fn field_prefix(&self) -> Option<(usize, usize)> {
+    let colon = self.chars[self.pos..].iter().take_while(|c| is_term_char(**c)).position(|c| *c == ':')?;
+    let name: String = self.chars[self.pos..self.pos + colon].iter().collect();
+    let field = self.index.fields.iter().position(|x| x.name == name)?;
+    let next = self.chars.get(self.pos + colon + 1);
+    if next.map_or(true, |c| c.is_whitespace() || *c == ')') {
+      return None;
+    }
+    return Some((field, colon + 1));
+  }
+
+  fn parse_term(&mut self, field: Option<usize>) -> Result<Node, String> {
+    if field.is_none() {
+      if let Some((field, length)) = self.field_prefix() {
+        self.pos += length;
+        return self.parse_primary(Some(field));
+      }
+    }
+
+    let start = self.pos;
+    while self.peek().map_or(false, is_term_char) {
+      self.pos += 1;
+    }
+    let text: String = self.chars[start..self.pos].iter().collect();
+
+    if text.contains('~') {
+      return self.error("'~' can only follow a quoted phrase");
+    }
+
+    if text.ends_with('*') {
+      let prefix = text[..text.len() - 1].to_string();
+      if prefix.len() == 0 || prefix.contains('*') {
+        return self.error("Expected a prefix before '*'");
+      }
+      let words = self.analyze(field, |x| vec![(x.normalize(&prefix), 0)]);
+      return Ok(Node::Prefix { prefix: prefix.to_lowercase(), words });
+    }
+    if text.contains('*') {
+      return self.error("'*' is only supported at the end of a term");
+    }
+
+    let words = self.analyze(field, |x| x.words(&text));
+    return Ok(Node::Term { text, words, field });
+  }
+}
+
+// Number of clauses from the start of `keys` that spell the longest
+// synonym phrase, with the phrases of every rule for it. Zero if there is
+// none.
+fn longest_synonym<'a>(
+  keys: &[Option<(Option<usize>, Vec<String>)>],
+  occurs: &[Occur],
+  sources: &Vec<Vec<String>>,
+  expansions: &Vec<(&String, Vec<&'a String>)>,
+) -> (usize, Vec<&'a String>) {
+  let field = keys[0].as_ref().unwrap().0;
+  let longest_source = sources.iter().map(|x| x.len()).max().unwrap_or(0);
+
+  let mut best = (0, Vec::new());
+  let mut terms: Vec<String> = Vec::new();
+  for (j, key) in keys.iter().enumerate() {
+    let key = match key {
+      Some((key_field, key)) if *key_field == field => key,
+      _ => break,
+    };
+    if j > 0 && (occurs[0] != Occur::Should || occurs[j] != Occur::Should) {
+      break;
+    }
+    terms.extend(key.iter().cloned());
+    if terms.len() > longest_source {
+      break;
+    }
+
+    let mut phrases: Vec<&String> = Vec::new();
+    for (source, expansion) in sources.iter().zip(expansions.iter()) {
+      if *source == terms {
+        for phrase in expansion.1.iter() {
+          if !phrases.contains(phrase) {
+            phrases.push(phrase);
+          }
+        }
+      }
+    }
+    if phrases.len() > 0 {
+      best = (j + 1, phrases);
+    }
+  }
+  return best;
+}
+
+// Parses the query syntax:
+//
+//   red dress          either word, ranked by how well they match
+//   +red -blue         has to contain red, must not contain blue
+//   red OR blue        either side has to match
+//   +(red OR blue)     parentheses group clauses
+//   name:red           only matches in the field "name", colons after
+//                      anything but a field name are text
+//   dre*               words starting with "dre"
+//   "red dress"~2      see Node::Phrase
+//   -red               every document without red
+//
+// A '+' or '-' on its own and quotes without a closing one are text.
+// Terms are expanded with the synonyms of the index.
+pub fn parse(query: &str, index: &Index) -> Result<Query, String> {
+  let mut parser = Parser {
+    chars: query.chars().collect(),
+    pos: 0,
+    index,
+    analyzers: index::analyzers(index),
+  };
+
+  let root = parser.parse_or()?;
+  if parser.pos < parser.chars.len() {
+    return parser.error("Unexpected ')'");
+  }
+
+  let mut query = Query { root, text: String::new() };
+
+  let mut text: Vec<&str> = Vec::new();
+  for leaf in query.positive_leaves() {
+    match leaf {
+      Node::Term { text: x, .. } | Node::Phrase { text: x, .. } => text.push(x),
+      Node::Prefix { prefix, .. } => text.push(prefix),
+      _ => {}
+    }
+  }
+  let text = text.join(" ").split_whitespace().collect::<Vec<&str>>().join(" ");
+  query.text = text;
+
+  // Only after the text, the fuzzy match is on what was typed
+  if index.synonyms.len() > 0 {
+    let expansions = synonyms::expansions(&index.synonyms);
+    let root = mem::replace(&mut query.root, Node::Or(Vec::new()));
+    query.root = parser.expand(root, &expansions);
+  }
+
+  return Ok(query);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::index::{Field, OnConflict, Settings};
+  use serde_json::json;
+
+  fn test_index() -> Index {
+    let fields: Vec<Field> = serde_json::from_value(json!(["name", "desc"])).unwrap();
+    let mut index = index::create(fields, Settings::default());
+    let items = vec![
+      json!({"_id": "1", "name": "red dress", "desc": "summer"}),
+      json!({"_id": "2", "name": "blue dress", "desc": "winter"}),
+      json!({"_id": "3", "name": "red shirt", "desc": "summer"}),
+    ];
+    for item in items {
+      index::add_object(&mut index, item, OnConflict::Reject).unwrap();
+    }
+    return index;
+  }
+
+  // The text and field of every clause of the top level group
+  fn clauses(query: &str, index: &Index) -> Vec<(String, Option<usize>)> {
+    let parsed = parse(query, index).unwrap();
+    match parsed.root {
+      Node::Group(clauses) => clauses.into_iter().map(|(_, node)| match node {
+        Node::Term { text, field, .. } | Node::Phrase { text, field, .. } => (text, field),
+        Node::Prefix { prefix, .. } => (prefix, None),
+        _ => panic!("unexpected node"),
+      }).collect(),
+      _ => panic!("expected a group"),
+    }
+  }
+
+  fn ids(query: &str, index: &Index) -> Vec<String> {
+    let mut ids: Vec<String> = index::search(index, query.to_string(), false, &|_| true, false)
+      .unwrap()
+      .hits
+      .iter()
+      .map(|x| index.items[&x.iid]["_id"].as_str().unwrap().to_string())
+      .collect();
+    ids.sort();
+    return ids;
+  }
+
+  #[test]
+  fn colons_after_other_words_are_text() {
+    let index = test_index();
+    assert_eq!(clauses("Star Wars: A New Hope", &index).len(), 5);
+    assert!(clauses("Star Wars: A New Hope", &index).iter().all(|x| x.1.is_none()));
+    assert_eq!(clauses("Mission: Impossible", &index)[0], (String::from("Mission:"), None));
+    assert_eq!(clauses("10:30", &index), vec![(String::from("10:30"), None)]);
+    assert_eq!(clauses("wars:", &index), vec![(String::from("wars:"), None)]);
+    // A field name without anything after the colon
+    assert_eq!(clauses("name:", &index), vec![(String::from("name:"), None)]);
+  }
+
+  #[test]
+  fn field_names_scope_terms() {
+    let index = test_index();
+    assert_eq!(clauses("name:red", &index), vec![(String::from("red"), Some(0))]);
+    assert_eq!(clauses("desc:\"summer\"", &index), vec![(String::from("summer"), Some(1))]);
+    assert_eq!(ids("+desc:summer", &index), vec!["1", "3"]);
+    assert!(parse("name:(red)", &index).is_err());
+  }
+
+  #[test]
+  fn lone_quotes_and_signs_are_text() {
+    let index = test_index();
+    assert_eq!(clauses("12\" pizza", &index), vec![(String::from("12"), None), (String::from("pizza"), None)]);
+    assert_eq!(clauses("\"red", &index), vec![(String::from("red"), None)]);
+    assert_eq!(clauses("- fallout", &index), vec![(String::from("fallout"), None)]);
+    assert_eq!(clauses("Mission: Impossible - Fallout", &index).len(), 3);
+    assert_eq!(clauses("red +", &index), vec![(String::from("red"), None)]);
+    assert!(parse("-", &index).is_ok());
+    assert!(parse("\"", &index).is_ok());
+  }
+
+  #[test]
+  fn syntax_errors_are_still_rejected() {
+    let index = test_index();
+    assert!(parse("(red", &index).is_err());
+    assert!(parse("red)", &index).is_err());
+    assert!(parse("()", &index).is_err());
+    assert!(parse("red~2", &index).is_err());
+    assert!(parse("ab*c", &index).is_err());
+  }
+
+  #[test]
+  fn only_excluded_terms_match_everything_else() {
+    let index = test_index();
+    assert_eq!(ids("-red", &index), vec!["2"]);
+    assert_eq!(ids("-red -blue", &index), Vec::<String>::new());
+    assert_eq!(ids("-name:dress", &index), vec!["3"]);
+    assert_eq!(ids("-", &index), vec!["1", "2", "3"]);
+  }
+
+  fn clothes_index(synonyms: serde_json::Value) -> Index {
+    let fields: Vec<Field> = serde_json::from_value(json!(["name"])).unwrap();
+    let mut index = index::create(fields, Settings::default());
+    let items = vec![
+      json!({"_id": "1", "name": "cotton t-shirt"}),
+      json!({"_id": "2", "name": "formal dress shirt"}),
+      json!({"_id": "3", "name": "leather sofa"}),
+      json!({"_id": "4", "name": "corner couch"}),
+      json!({"_id": "5", "name": "tee ball set"}),
+    ];
+    for item in items {
+      index::add_object(&mut index, item, OnConflict::Reject).unwrap();
+    }
+    index.synonyms = serde_json::from_value(synonyms).unwrap();
+    return index;
+  }
+
+  #[test]
+  fn equivalent_synonyms_find_each_other() {
+    let index = clothes_index(json!([["sofa", "couch"]]));
+    assert_eq!(ids("sofa", &index), vec!["3", "4"]);
+    assert_eq!(ids("couch", &index), vec!["3", "4"]);
+    assert_eq!(ids("+couch", &index), vec!["3", "4"]);
+  }
+
+  #[test]
+  fn one_way_synonyms_only_expand_their_source() {
+    let index = clothes_index(json!([{"from": ["couch"], "to": ["sofa"]}]));
+    assert_eq!(ids("couch", &index), vec!["3", "4"]);
+    assert_eq!(ids("sofa", &index), vec!["3"]);
+  }
+
+  #[test]
+  fn synonym_phrases_keep_short_words() {
+    let index = clothes_index(json!([["tee", "t-shirt"]]));
+    // "t" is too short to be indexed, "dress shirt" has "shirt" but not "t shirt"
+    assert_eq!(ids("tee", &index), vec!["1", "5"]);
+    assert_eq!(ids("+tee", &index), vec!["1", "5"]);
+    assert_eq!(ids("cotton tee", &index), vec!["1"]);
+  }
+}
@@ -0,0 +1,376 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::Ordering;
+
+use crate::index;
+use crate::index::{Field, Index, Settings};
+use crate::persist;
+use crate::wal;
+use crate::wal::{ItemResult, Operation, Wal};
+
+pub type SharedIndex = Arc<RwLock<Index>>;
+
+// All indexes by name. The map itself is only write locked to create or
+// drop indexes, and only read locked long enough to look an index up;
+// everything else then takes the lock of the one index it works on, so a
+// bulk import into one index does not block searches on the others or
+// the creation of new ones.
+pub struct Registry {
+  indexes: RwLock<HashMap<String, SharedIndex>>,
+  wal: Mutex<Option<Wal>>,
+}
+
+fn is_stale(shared: &SharedIndex, seq: u64) -> bool {
+  wal::is_stale(&shared.read().unwrap(), seq)
+}
+
+// Marks an index that was removed from the map, see Registry::write
+fn mark_dropped(shared: &SharedIndex) {
+  shared.write().unwrap().dropped = true;
+}
+
+// Applies any operation to the map, used for registry level operations
+// and when replaying the log
+fn apply(indexes: &mut HashMap<String, SharedIndex>, seq: u64, op: Operation) {
+  match op {
+    Operation::CreateIndex { index: name, fields, settings } => {
+      if indexes.get(&name).map_or(false, |x| is_stale(x, seq)) {
+        return;
+      }
+      let mut created = index::create(fields, settings);
+      created.last_seq = seq;
+      if let Some(replaced) = indexes.insert(name, Arc::new(RwLock::new(created))) {
+        mark_dropped(&replaced);
+      }
+    }
+    Operation::DeleteIndex { index: name } => {
+      if indexes.get(&name).map_or(false, |x| is_stale(x, seq)) {
+        return;
+      }
+      if let Some(removed) = indexes.remove(&name) {
+        mark_dropped(&removed);
+      }
+    }
+    Operation::ClearAll => {
+      indexes.retain(|_, x| {
+        if is_stale(x, seq) {
+          return true;
+        }
+        mark_dropped(x);
+        return false;
+      });
+      indexes.shrink_to_fit();
+    }
+    op => {
+      let target = op.index_name().and_then(|name| indexes.get(name));
+      if let Some(target) = target {
+        wal::apply(&mut target.write().unwrap(), seq, op);
+      }
+    }
+  }
+}
+
+impl Registry {
+  pub fn new() -> Registry {
+    Registry {
+      indexes: RwLock::new(HashMap::new()),
+      wal: Mutex::new(None),
+    }
+  }
+
+  fn log(&self, op: &Operation) -> io::Result<u64> {
+    match self.wal.lock().unwrap().as_mut() {
+      Some(log) => log.append(op),
+      None => Ok(0),
+    }
+  }
+
+  pub fn get(&self, name: &str) -> Option<SharedIndex> {
+    self.indexes.read().unwrap().get(name).cloned()
+  }
+
+  // Logs an item level operation and applies it to its index. Returns the
+  // outcome per item, or None when the index does not exist.
+  pub fn write(&self, op: Operation) -> io::Result<Option<Vec<ItemResult>>> {
+    let name = match op.index_name() {
+      Some(name) => name.to_string(),
+      None => return Ok(None),
+    };
+
+    // The map lock is only held for the lookup. An index dropped before we
+    // get its lock is marked so, then the name may belong to a new index.
+    // Once we hold the lock it can't be dropped until the operation is
+    // logged and applied.
+    loop {
+      let shared = match self.get(&name) {
+        Some(x) => x,
+        None => return Ok(None),
+      };
+
+      let mut target = shared.write().unwrap();
+      if target.dropped {
+        continue;
+      }
+      let seq = self.log(&op)?;
+      return Ok(Some(wal::apply(&mut target, seq, op)));
+    }
+  }
+
+  // Returns false when an index with that name already exists
+  pub fn create(&self, name: String, fields: Vec<Field>, settings: Settings) -> io::Result<bool> {
+    let mut indexes = self.indexes.write().unwrap();
+
+    if indexes.contains_key(&name) {
+      return Ok(false);
+    }
+
+    let op = Operation::CreateIndex { index: name, fields, settings };
+    let seq = self.log(&op)?;
+    apply(&mut indexes, seq, op);
+    return Ok(true);
+  }
+
+  // Returns false when the index does not exist
+  pub fn delete(&self, name: String) -> io::Result<bool> {
+    let mut indexes = self.indexes.write().unwrap();
+
+    if !indexes.contains_key(&name) {
+      return Ok(false);
+    }
+
+    let op = Operation::DeleteIndex { index: name };
+    let seq = self.log(&op)?;
+    apply(&mut indexes, seq, op);
+    return Ok(true);
+  }
+
+  pub fn clear(&self) -> io::Result<()> {
+    let mut indexes = self.indexes.write().unwrap();
+
+    let op = Operation::ClearAll;
+    let seq = self.log(&op)?;
+    apply(&mut indexes, seq, op);
+    return Ok(());
+  }
+
+  pub fn sync_log(&self) -> io::Result<()> {
+    match self.wal.lock().unwrap().as_mut() {
+      Some(log) => log.sync(),
+      None => Ok(()),
+    }
+  }
+
+  // Loads the snapshots, replays the log on top of them and starts logging
+  pub fn restore(&self, dir: &Path, fsync: wal::FsyncPolicy) -> io::Result<()> {
+    // Built on the side, the map is only locked to swap it in
+    let mut indexes = HashMap::new();
+
+    for (name, index) in persist::load_all(dir)? {
+      indexes.insert(name, Arc::new(RwLock::new(index)));
+    }
+    println!("Loaded {} indexes from {}", indexes.len(), dir.display());
+
+    let mut seq = indexes
+      .values()
+      .map(|x| x.read().unwrap().last_seq)
+      .max()
+      .unwrap_or(0);
+
+    let entries = wal::read_all(dir)?;
+    println!("Replaying {} logged operations", entries.len());
+    for entry in entries {
+      seq = cmp::max(seq, entry.seq);
+      apply(&mut indexes, entry.seq, entry.op);
+    }
+
+    let wal = Wal::open(dir, fsync, seq)?;
+    *self.indexes.write().unwrap() = indexes;
+    *self.wal.lock().unwrap() = Some(wal);
+    return Ok(());
+  }
+
+  // Writes a snapshot of every index that changed since its last snapshot,
+  // then drops the log files those snapshots now cover
+  pub fn snapshot(&self, dir: &Path) {
+    // Operations are logged while holding the lock of the index (or of the
+    // map) they apply to, so everything in the rotated out files is visible
+    // once we get those locks below
+    let mut generation = None;
+    if let Some(log) = self.wal.lock().unwrap().as_mut() {
+      match log.rotate() {
+        Ok(g) => generation = Some(g),
+        Err(err) => println!("Failed to rotate operation log: {}", err),
+      }
+    }
+
+    // Indexes created before the rotation are in the map by now. The map
+    // lock is released before writing, indexes dropped since are skipped.
+    let indexes: Vec<(String, SharedIndex)> = self.indexes
+      .read()
+      .unwrap()
+      .iter()
+      .map(|(name, shared)| (name.clone(), shared.clone()))
+      .collect();
+    let mut complete = true;
+
+    for (name, shared) in indexes.iter() {
+      let index = shared.read().unwrap();
+      if index.dropped || !index.dirty.load(Ordering::Relaxed) {
+        continue;
+      }
+
+      match persist::save(dir, name, &index) {
+        Ok(_) => index.dirty.store(false, Ordering::Relaxed),
+        Err(err) => {
+          println!("Failed to snapshot index '{}': {}", name, err);
+          complete = false;
+        }
+      }
+    }
+
+    let names: Vec<&String> = indexes.iter().map(|x| &x.0).collect();
+    if let Err(err) = persist::prune(dir, &names) {
+      println!("Failed to remove stale snapshots: {}", err);
+      complete = false;
+    }
+
+    if let (true, Some(generation)) = (complete, generation) {
+      if let Err(err) = wal::remove_before(dir, generation) {
+        println!("Failed to remove old operation logs: {}", err);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::fs::OpenOptions;
+  use std::io::Write;
+  use std::path::PathBuf;
+  use crate::index::OnConflict;
+  use serde_json::json;
+
+  fn fields() -> Vec<Field> {
+    serde_json::from_value(json!(["name"])).unwrap()
+  }
+
+  fn add_to(registry: &Registry, name: &str, id: &str) -> Option<Vec<ItemResult>> {
+    let items = vec![json!({"_id": id, "name": "red dress"})];
+    let op = Operation::AddItems { index: String::from(name), items, on_conflict: OnConflict::Reject, atomic: false };
+    registry.write(op).unwrap()
+  }
+
+  fn add(registry: &Registry, id: &str) -> Option<Vec<ItemResult>> {
+    add_to(registry, "a", id)
+  }
+
+  // An empty directory of the test's own
+  fn data_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("gianna-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    return dir;
+  }
+
+  // Files in dir with the extension, sorted
+  fn files(dir: &Path, extension: &str) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+      .unwrap()
+      .map(|x| x.unwrap().path())
+      .filter(|x| x.extension().map_or(false, |ext| ext == extension))
+      .collect();
+    files.sort();
+    return files;
+  }
+
+  fn append(path: &Path, text: &str) {
+    OpenOptions::new().append(true).open(path).unwrap().write_all(text.as_bytes()).unwrap();
+  }
+
+  fn ids(registry: &Registry, name: &str) -> Vec<String> {
+    let shared = registry.get(name).unwrap();
+    let mut ids: Vec<String> = shared.read().unwrap().id_map.keys().cloned().collect();
+    ids.sort();
+    return ids;
+  }
+
+  #[test]
+  fn writes_go_to_the_index_that_has_the_name_now() {
+    let registry = Registry::new();
+    assert!(registry.create(String::from("a"), fields(), Settings::default()).unwrap());
+    assert!(add(&registry, "1").is_some());
+
+    let old = registry.get("a").unwrap();
+    assert!(registry.delete(String::from("a")).unwrap());
+    assert!(old.read().unwrap().dropped);
+    assert!(add(&registry, "2").is_none());
+
+    assert!(registry.create(String::from("a"), fields(), Settings::default()).unwrap());
+    assert!(add(&registry, "3").is_some());
+    let new = registry.get("a").unwrap();
+    assert!(!new.read().unwrap().dropped);
+    assert_eq!(new.read().unwrap().id_map.len(), 1);
+    assert_eq!(old.read().unwrap().id_map.len(), 1);
+
+    registry.clear().unwrap();
+    assert!(new.read().unwrap().dropped);
+    assert!(registry.get("a").is_none());
+  }
+
+  #[test]
+  fn snapshots_and_the_log_restore_every_index() {
+    let dir = data_dir("restore");
+    let fsync = wal::FsyncPolicy::Never;
+
+    let registry = Registry::new();
+    registry.restore(&dir, fsync).unwrap();
+    registry.create(String::from("a"), fields(), Settings::default()).unwrap();
+    add(&registry, "1");
+    registry.create(String::from("b"), fields(), Settings::default()).unwrap();
+    add_to(&registry, "b", "x");
+
+    // Only the log started by the snapshot is left
+    registry.snapshot(&dir);
+    assert_eq!(files(&dir, "snapshot").len(), 2);
+    assert_eq!(files(&dir, "log").len(), 1);
+
+    add(&registry, "2");
+    registry.delete(String::from("b")).unwrap();
+    registry.create(String::from("b"), fields(), Settings::default()).unwrap();
+    add_to(&registry, "b", "y");
+    registry.sync_log().unwrap();
+
+    // An entry the snapshot of "a" already has, as if the process stopped
+    // before removing the log it was in, then a torn write
+    let log = files(&dir, "log").pop().unwrap();
+    append(&log, "{\"seq\":2,\"op\":\"add_items\",\"index\":\"a\",\"items\":[{\"_id\":\"3\",\"name\":\"red\"}]}\n");
+    append(&log, "{\"seq\":9,\"op\":\"add_it");
+
+    let restored = Registry::new();
+    restored.restore(&dir, fsync).unwrap();
+    assert_eq!(ids(&restored, "a"), vec!["1", "2"]);
+    assert_eq!(ids(&restored, "b"), vec!["y"]);
+
+    // Logging goes on in a new file after the torn one
+    add_to(&restored, "a", "4");
+    restored.sync_log().unwrap();
+    let again = Registry::new();
+    again.restore(&dir, fsync).unwrap();
+    assert_eq!(ids(&again, "a"), vec!["1", "2", "4"]);
+    assert_eq!(ids(&again, "b"), vec!["y"]);
+
+    // Snapshots cover the whole log, the older files go
+    again.snapshot(&dir);
+    assert_eq!(files(&dir, "log").len(), 1);
+    let last = Registry::new();
+    last.restore(&dir, fsync).unwrap();
+    assert_eq!(ids(&last, "a"), vec!["1", "2", "4"]);
+    assert_eq!(ids(&last, "b"), vec!["y"]);
+
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
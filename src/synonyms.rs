@@ -0,0 +1,56 @@
+// Synonym rules of an index, applied to queries only so they can change
+// without reindexing. Phrases can have several words, "t shirt" matches
+// the query terms t and shirt next to each other.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Synonyms {
+  // Every phrase also finds the others
+  Equivalent(Vec<String>),
+  // The phrases in `from` also find the ones in `to`, not the other way
+  // around
+  OneWay { from: Vec<String>, to: Vec<String> },
+}
+
+pub fn validate(synonyms: &Vec<Synonyms>) -> Result<(), String> {
+  for (i, rule) in synonyms.iter().enumerate() {
+    let phrases = match rule {
+      Synonyms::Equivalent(phrases) => {
+        if phrases.len() < 2 {
+          return Err(format!("Rule {} needs at least two phrases", i));
+        }
+        phrases.iter().collect::<Vec<&String>>()
+      }
+      Synonyms::OneWay { from, to } => {
+        if from.len() == 0 || to.len() == 0 {
+          return Err(format!("Rule {} needs phrases in both 'from' and 'to'", i));
+        }
+        from.iter().chain(to.iter()).collect()
+      }
+    };
+
+    if phrases.iter().any(|x| x.trim().len() == 0) {
+      return Err(format!("Rule {} has an empty phrase", i));
+    }
+  }
+  return Ok(());
+}
+
+// Every phrase with the phrases it also finds
+pub fn expansions(synonyms: &Vec<Synonyms>) -> Vec<(&String, Vec<&String>)> {
+  let mut expansions = Vec::new();
+  for rule in synonyms {
+    match rule {
+      Synonyms::Equivalent(phrases) => {
+        for phrase in phrases {
+          expansions.push((phrase, phrases.iter().filter(|x| *x != phrase).collect()));
+        }
+      }
+      Synonyms::OneWay { from, to } => {
+        for phrase in from {
+          expansions.push((phrase, to.iter().collect()));
+        }
+      }
+    }
+  }
+  return expansions;
+}
@@ -0,0 +1,248 @@
+use std::cmp;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::lp::fold_accents;
+
+// One char of a key. Nodes are never removed, a word that no document has
+// anymore keeps its nodes with a count of 0.
+struct Node {
+  children: Vec<(char, u32)>,
+  parent: u32,
+  // Documents with the key that ends here
+  count: u32,
+  // Highest count in this subtree, so a lookup can go to the most common
+  // completions first
+  best: u32,
+  // The key as it is shown, set while count > 0
+  text: Option<Box<str>>,
+}
+
+impl Node {
+  fn new(parent: u32) -> Node {
+    Node { children: Vec::new(), parent, count: 0, best: 0, text: None }
+  }
+}
+
+// Prefix tree from keys to the number of documents they are in, for
+// completing words as they are typed
+pub struct Trie {
+  nodes: Vec<Node>,
+}
+
+#[derive(Serialize)]
+pub struct Completion {
+  pub text: String,
+  pub count: u32,
+}
+
+// Keys are matched lowercased and without accents
+pub fn key(s: &str) -> String {
+  fold_accents(&s.to_lowercase())
+}
+
+impl Default for Trie {
+  fn default() -> Trie {
+    Trie { nodes: vec![Node::new(0)] }
+  }
+}
+
+impl Trie {
+  fn child(&self, node: u32, c: char) -> Option<u32> {
+    let children = &self.nodes[node as usize].children;
+    children.binary_search_by_key(&c, |x| x.0).ok().map(|i| children[i].1)
+  }
+
+  fn find(&self, key: &str) -> Option<u32> {
+    let mut node = 0;
+    for c in key.chars() {
+      node = self.child(node, c)?;
+    }
+    return Some(node);
+  }
+
+  fn find_or_insert(&mut self, key: &str) -> u32 {
+    let mut node = 0;
+    for c in key.chars() {
+      node = match self.nodes[node as usize].children.binary_search_by_key(&c, |x| x.0) {
+        Ok(i) => self.nodes[node as usize].children[i].1,
+        Err(i) => {
+          let id = self.nodes.len() as u32;
+          self.nodes.push(Node::new(node));
+          self.nodes[node as usize].children.insert(i, (c, id));
+          id
+        }
+      };
+    }
+    return node;
+  }
+
+  // Recomputes the best counts from node up to the root, stops as soon as
+  // a subtree didn't change
+  fn update_best(&mut self, mut node: u32) {
+    loop {
+      let current = &self.nodes[node as usize];
+      let best = current.children
+        .iter()
+        .map(|x| self.nodes[x.1 as usize].best)
+        .fold(current.count, cmp::max);
+
+      if best == current.best {
+        return;
+      }
+      self.nodes[node as usize].best = best;
+      if node == 0 {
+        return;
+      }
+      node = self.nodes[node as usize].parent;
+    }
+  }
+
+  // Counts one more document for text, which is shown as it is first added
+  pub fn add(&mut self, text: &str) {
+    let key = key(text);
+    if key.len() == 0 {
+      return;
+    }
+
+    let node = self.find_or_insert(&key);
+    let entry = &mut self.nodes[node as usize];
+    entry.count += 1;
+    if entry.text.is_none() {
+      entry.text = Some(text.into());
+    }
+    self.update_best(node);
+  }
+
+  pub fn remove(&mut self, text: &str) {
+    let node = match self.find(&key(text)) {
+      Some(x) => x,
+      None => return,
+    };
+
+    let entry = &mut self.nodes[node as usize];
+    if entry.count == 0 {
+      return;
+    }
+    entry.count -= 1;
+    if entry.count == 0 {
+      entry.text = None;
+    }
+    self.update_best(node);
+  }
+
+  // The keys starting with prefix in the most documents, best first
+  pub fn complete(&self, prefix: &str, limit: usize) -> Vec<Completion> {
+    let mut completions = Vec::new();
+    let start = match self.find(&key(prefix)) {
+      Some(x) => x,
+      None => return completions,
+    };
+
+    // Subtrees by their best count and keys by their own count, a key
+    // comes out once no subtree left can beat it. Ties go to what was
+    // reached first, shorter keys and then lower chars.
+    let mut order = 0;
+    let mut heap = BinaryHeap::new();
+    heap.push((self.nodes[start as usize].best, false, Reverse(order), start));
+    while let Some((count, is_key, _, node)) = heap.pop() {
+      if count == 0 || completions.len() >= limit {
+        break;
+      }
+
+      let current = &self.nodes[node as usize];
+      if is_key {
+        completions.push(Completion {
+          text: current.text.as_ref().unwrap().to_string(),
+          count,
+        });
+        continue;
+      }
+
+      if current.count > 0 {
+        order += 1;
+        heap.push((current.count, true, Reverse(order), node));
+      }
+      for (_, child) in current.children.iter() {
+        order += 1;
+        heap.push((self.nodes[*child as usize].best, false, Reverse(order), *child));
+      }
+    }
+
+    return completions;
+  }
+
+  // Approximate heap usage in bytes
+  pub fn memory_usage(&self) -> usize {
+    self.nodes.capacity() * std::mem::size_of::<Node>()
+      + self.nodes
+        .iter()
+        .map(|x| x.children.capacity() * 8 + x.text.as_ref().map_or(0, |t| t.len()))
+        .sum::<usize>()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn completions(trie: &Trie, prefix: &str, limit: usize) -> Vec<(String, u32)> {
+    trie.complete(prefix, limit).into_iter().map(|x| (x.text, x.count)).collect()
+  }
+
+  fn clothes() -> Trie {
+    let mut trie = Trie::default();
+    for text in ["Shirt", "shirt", "shirt", "shirts", "shoes", "short", "shoes", "short", "sandals"].iter() {
+      trie.add(text);
+    }
+    return trie;
+  }
+
+  #[test]
+  fn completions_come_most_common_first() {
+    let trie = clothes();
+    let expected = vec![
+      (String::from("Shirt"), 3),
+      (String::from("shoes"), 2),
+      (String::from("short"), 2),
+      (String::from("shirts"), 1),
+    ];
+    assert_eq!(completions(&trie, "sh", 10), expected);
+    assert_eq!(completions(&trie, "sh", 2), expected[..2].to_vec());
+    assert_eq!(completions(&trie, "s", 10)[4], (String::from("sandals"), 1));
+    assert!(completions(&trie, "x", 10).is_empty());
+  }
+
+  #[test]
+  fn removed_keys_drop_their_count_and_text() {
+    let mut trie = clothes();
+    trie.remove("SHIRT");
+    trie.remove("shirt");
+    assert_eq!(completions(&trie, "shi", 10), vec![(String::from("Shirt"), 1), (String::from("shirts"), 1)]);
+
+    // Once no document has the key, the next one decides how it is shown
+    trie.remove("shirt");
+    assert_eq!(completions(&trie, "shi", 10), vec![(String::from("shirts"), 1)]);
+    trie.add("SHIRT");
+    assert_eq!(completions(&trie, "shirt", 1), vec![(String::from("SHIRT"), 1)]);
+
+    // Keys that aren't there, or not anymore, change nothing
+    trie.remove("sandals");
+    trie.remove("sandals");
+    trie.remove("boots");
+    assert!(completions(&trie, "sa", 10).is_empty());
+    assert_eq!(completions(&trie, "sho", 10).len(), 2);
+  }
+
+  #[test]
+  fn keys_are_folded() {
+    let mut trie = Trie::default();
+    trie.add("Café Crème");
+    trie.add("cafe creme");
+    trie.add("");
+    for prefix in ["cafe", "CAFÉ", "café cr", "Cafe Creme"].iter() {
+      assert_eq!(completions(&trie, prefix, 10), vec![(String::from("Café Crème"), 2)]);
+    }
+    assert!(completions(&trie, "cafes", 10).is_empty());
+  }
+}
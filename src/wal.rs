@@ -0,0 +1,378 @@
+use std::collections::HashSet;
+use std::fs;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+use serde_json::{Value};
+
+use crate::index;
+use crate::index::{Field, Index, ItemStatus, OnConflict, Settings};
+use crate::synonyms::Synonyms;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FsyncPolicy {
+  Always,
+  Interval(u64),
+  Never,
+}
+
+impl FsyncPolicy {
+  // "always", "never" or an interval in milliseconds
+  pub fn parse(s: &str) -> Option<FsyncPolicy> {
+    match s {
+      "always" => Some(FsyncPolicy::Always),
+      "never" => Some(FsyncPolicy::Never),
+      _ => s.parse().ok().map(FsyncPolicy::Interval),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+  CreateIndex {
+    index: String,
+    fields: Vec<Field>,
+    #[serde(default)]
+    settings: Settings,
+  },
+  UpdateSettings { index: String, settings: Settings },
+  UpdateSynonyms { index: String, synonyms: Vec<Synonyms> },
+  // In atomic operations a single invalid item rejects all of them
+  AddItems {
+    index: String,
+    items: Vec<Value>,
+    #[serde(default)]
+    on_conflict: OnConflict,
+    #[serde(default)]
+    atomic: bool,
+  },
+  UpdateItems {
+    index: String,
+    items: Vec<Value>,
+    #[serde(default)]
+    atomic: bool,
+  },
+  DeleteItems {
+    index: String,
+    ids: Vec<Value>,
+    #[serde(default)]
+    atomic: bool,
+  },
+  ClearIndex { index: String },
+  DeleteIndex { index: String },
+  ClearAll,
+}
+
+impl Operation {
+  pub fn index_name(&self) -> Option<&String> {
+    match self {
+      Operation::CreateIndex { index, .. } => Some(index),
+      Operation::AddItems { index, .. } => Some(index),
+      Operation::UpdateItems { index, .. } => Some(index),
+      Operation::DeleteItems { index, .. } => Some(index),
+      Operation::ClearIndex { index } => Some(index),
+      Operation::UpdateSettings { index, .. } => Some(index),
+      Operation::UpdateSynonyms { index, .. } => Some(index),
+      Operation::DeleteIndex { index } => Some(index),
+      Operation::ClearAll => None,
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct EntryRef<'a> {
+  seq: u64,
+  #[serde(flatten)]
+  op: &'a Operation,
+}
+
+#[derive(Deserialize)]
+pub struct Entry {
+  pub seq: u64,
+  #[serde(flatten)]
+  pub op: Operation,
+}
+
+pub struct Wal {
+  dir: PathBuf,
+  file: File,
+  generation: u64,
+  seq: u64,
+  policy: FsyncPolicy,
+  unsynced: bool,
+}
+
+fn log_path(dir: &Path, generation: u64) -> PathBuf {
+  dir.join(format!("wal-{:010}.log", generation))
+}
+
+// Existing log files, oldest first
+fn log_files(dir: &Path) -> io::Result<Vec<(u64, PathBuf)>> {
+  let mut files = Vec::new();
+
+  if !dir.exists() {
+    return Ok(files);
+  }
+
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    let generation = path
+      .file_name()
+      .and_then(|name| name.to_str())
+      .filter(|name| name.starts_with("wal-") && name.ends_with(".log"))
+      .and_then(|name| name[4..name.len() - 4].parse::<u64>().ok());
+
+    if let Some(generation) = generation {
+      files.push((generation, path));
+    }
+  }
+
+  files.sort_by(|a, b| a.0.cmp(&b.0));
+  return Ok(files);
+}
+
+fn open_log(dir: &Path, generation: u64) -> io::Result<File> {
+  OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(log_path(dir, generation))
+}
+
+impl Wal {
+  // Starts a fresh log file after the existing ones, which are kept until
+  // the next successful snapshot. `seq` is the last sequence number already
+  // applied, either from a replayed log or from a snapshot.
+  pub fn open(dir: &Path, policy: FsyncPolicy, seq: u64) -> io::Result<Wal> {
+    fs::create_dir_all(dir)?;
+
+    let generation = log_files(dir)?.last().map_or(0, |x| x.0 + 1);
+    let file = open_log(dir, generation)?;
+
+    Ok(Wal {
+      dir: dir.to_path_buf(),
+      file,
+      generation,
+      seq,
+      policy,
+      unsynced: false,
+    })
+  }
+
+  // Records an operation and returns its sequence number
+  pub fn append(&mut self, op: &Operation) -> io::Result<u64> {
+    let seq = self.seq + 1;
+
+    let mut line = serde_json::to_vec(&EntryRef { seq, op })?;
+    line.push(b'\n');
+    self.file.write_all(&line)?;
+
+    if self.policy == FsyncPolicy::Always {
+      self.file.sync_data()?;
+    }
+    else {
+      self.unsynced = true;
+    }
+
+    self.seq = seq;
+    return Ok(seq);
+  }
+
+  pub fn sync(&mut self) -> io::Result<()> {
+    if self.unsynced {
+      self.file.sync_data()?;
+      self.unsynced = false;
+    }
+    return Ok(());
+  }
+
+  // Switches to a new log file and returns its generation. Once every
+  // index has been snapshotted, files before that generation can go.
+  pub fn rotate(&mut self) -> io::Result<u64> {
+    if self.policy != FsyncPolicy::Never {
+      self.sync()?;
+    }
+
+    let generation = self.generation + 1;
+    self.file = open_log(&self.dir, generation)?;
+    self.generation = generation;
+    return Ok(generation);
+  }
+}
+
+pub fn remove_before(dir: &Path, generation: u64) -> io::Result<()> {
+  for (file_generation, path) in log_files(dir)? {
+    if file_generation < generation {
+      fs::remove_file(path)?;
+    }
+  }
+  return Ok(());
+}
+
+pub fn read_all(dir: &Path) -> io::Result<Vec<Entry>> {
+  let mut entries = Vec::new();
+
+  for (_, path) in log_files(dir)? {
+    let reader = BufReader::new(File::open(&path)?);
+
+    for line in reader.lines() {
+      let line = line?;
+      if line.trim().is_empty() {
+        continue;
+      }
+
+      match serde_json::from_str::<Entry>(&line) {
+        Ok(entry) => entries.push(entry),
+        Err(err) => {
+          // A torn write at the end of a log is expected after a crash
+          println!("Stopped reading {} at a corrupt entry: {}", path.display(), err);
+          break;
+        }
+      }
+    }
+  }
+
+  return Ok(entries);
+}
+
+// Already contained in the snapshot the index was loaded from
+pub fn is_stale(index: &Index, seq: u64) -> bool {
+  seq != 0 && index.last_seq >= seq
+}
+
+// Outcome for one item of a bulk operation
+#[derive(Serialize)]
+pub struct ItemResult {
+  // Position of the item in the request
+  pub index: usize,
+  pub _id: Option<String>,
+  pub status: ItemStatus,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+impl ItemResult {
+  fn new(index: usize, id: Option<String>, result: Result<ItemStatus, String>) -> ItemResult {
+    match result {
+      Ok(status) => ItemResult { index, _id: id, status, error: None },
+      Err(error) => ItemResult { index, _id: id, status: ItemStatus::Error, error: Some(error) },
+    }
+  }
+}
+
+fn item_id(item: &Value) -> Option<String> {
+  item["_id"].as_str().map(String::from)
+}
+
+fn validate_id(id: &Value) -> Result<String, String> {
+  match id {
+    Value::String(id) => Ok(id.clone()),
+    _ => Err(String::from("_id must be a string")),
+  }
+}
+
+// Checks every item of an atomic operation up front. Returns the results
+// to report when at least one item would fail.
+fn check_atomic(target: &Index, op: &Operation) -> Option<Vec<ItemResult>> {
+  let checks: Vec<(Option<String>, Result<(), String>)> = match op {
+    Operation::AddItems { items, on_conflict, atomic: true, .. } => {
+      let mut seen = HashSet::new();
+      items.iter().map(|item| {
+        let check = index::validate_item(item).and_then(|id| {
+          let duplicate = target.id_map.contains_key(&id) || !seen.insert(id.clone());
+          if duplicate && *on_conflict == OnConflict::Reject {
+            return Err(index::conflict_error(&id));
+          }
+          Ok(())
+        });
+        (item_id(item), check)
+      }).collect()
+    }
+    Operation::UpdateItems { items, atomic: true, .. } => {
+      items.iter().map(|item| (item_id(item), index::validate_item(item).map(|_| ()))).collect()
+    }
+    Operation::DeleteItems { ids, atomic: true, .. } => {
+      ids.iter().map(|id| (id.as_str().map(String::from), validate_id(id).map(|_| ()))).collect()
+    }
+    _ => return None,
+  };
+
+  if checks.iter().all(|x| x.1.is_ok()) {
+    return None;
+  }
+
+  let results = checks.into_iter().enumerate().map(|(i, (id, check))| {
+    ItemResult::new(i, id, check.map(|_| ItemStatus::Skipped))
+  }).collect();
+  return Some(results);
+}
+
+// Applies an item level operation to its index and returns the outcome per
+// item. Used both by the routes, right after logging, and when replaying the
+// log on top of the snapshots. Registry level operations are handled by the
+// registry itself.
+pub fn apply(target: &mut Index, seq: u64, op: Operation) -> Vec<ItemResult> {
+  let mut results = Vec::new();
+
+  if is_stale(target, seq) {
+    return results;
+  }
+
+  if let Some(rejected) = check_atomic(target, &op) {
+    target.last_seq = seq;
+    return rejected;
+  }
+
+  match op {
+    Operation::AddItems { items, on_conflict, .. } => {
+      for (i, item) in items.into_iter().enumerate() {
+        let id = item_id(&item);
+        results.push(ItemResult::new(i, id, index::add_object(target, item, on_conflict)));
+      }
+    }
+    Operation::UpdateItems { items, .. } => {
+      for (i, item) in items.into_iter().enumerate() {
+        let id = item_id(&item);
+        results.push(ItemResult::new(i, id, index::update(target, item)));
+      }
+    }
+    Operation::DeleteItems { ids, .. } => {
+      for (i, id) in ids.into_iter().enumerate() {
+        let result = validate_id(&id).map(|id| {
+          if index::remove(target, id) {
+            ItemStatus::Deleted
+          } else {
+            ItemStatus::NotFound
+          }
+        });
+        results.push(ItemResult::new(i, id.as_str().map(String::from), result));
+      }
+    }
+    Operation::ClearIndex { .. } => {
+      index::clear(target);
+    }
+    Operation::UpdateSettings { settings, .. } => {
+      let reanalyze = index::analysis_changed(&target.settings, &settings);
+      let recomplete = target.settings.complete_values != settings.complete_values;
+      target.settings = settings;
+      if reanalyze {
+        index::reindex(target);
+      } else if recomplete {
+        index::rebuild_completions(target);
+      }
+      target.dirty.store(true, Ordering::Relaxed);
+    }
+    Operation::UpdateSynonyms { synonyms, .. } => {
+      target.synonyms = synonyms;
+      target.dirty.store(true, Ordering::Relaxed);
+    }
+    Operation::CreateIndex { .. } | Operation::DeleteIndex { .. } | Operation::ClearAll => {
+      return results;
+    }
+  }
+
+  target.last_seq = seq;
+  return results;
+}